@@ -0,0 +1,198 @@
+//! Framing for the tcp stream.
+//!
+//! Every frame is the `FRAME_ID` as a big endian `u16`, followed by the length of the message
+//! as a big endian `u32`, then the message itself.
+//!
+//! **Breaking change:** the length used to be written by `binary_util` as part of the message's
+//! `Vec<u8>`, it is now always a 4 byte big endian `u32`. Peers using the old framing can not
+//! talk to peers using this one.
+use super::{Frame, FRAME_HEADER_SIZE, FRAME_ID};
+
+/// The largest frame accepted by default, in bytes.
+/// Large messages are split, so frames are usually much smaller than this.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame does not start with `FRAME_ID`, the stream is out of sync.
+    InvalidId(u16),
+    /// The frame is larger than the codec's max frame size.
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::InvalidId(id) => write!(f, "Invalid frame ID: {}", id),
+            FrameError::TooLarge(size) => write!(f, "Frame is too large: {} bytes", size),
+        }
+    }
+}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+    }
+}
+
+/// Splits a TCP stream into frames.
+///
+/// Bytes are appended as they are read from the stream, in chunks of any size. A read may
+/// contain part of a frame or several frames, `decode` returns one complete frame at a time,
+/// and keeps any remaining bytes for the next read.
+///
+/// The codec does not do any IO.
+#[derive(Debug, Clone)]
+pub struct FrameCodec {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl FrameCodec {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Sets the largest message a frame can carry, larger frames are refused.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// The amount of bytes buffered that are not part of a complete frame yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Appends bytes read from the stream.
+    pub fn extend(&mut self, buf: &[u8]) {
+        self.buffer.extend_from_slice(buf);
+    }
+
+    /// Takes the next complete frame from the buffer, or `None` if more bytes are needed.
+    ///
+    /// An error means the stream can not be recovered, and the connection should be closed.
+    /// The size is checked before the message is buffered, so a peer can't make us buffer
+    /// more than a single frame.
+    pub fn decode(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let id = u16::from_be_bytes([self.buffer[0], self.buffer[1]]);
+
+        if id != FRAME_ID {
+            return Err(FrameError::InvalidId(id));
+        }
+
+        let size = u32::from_be_bytes([
+            self.buffer[2],
+            self.buffer[3],
+            self.buffer[4],
+            self.buffer[5],
+        ]) as usize;
+
+        if size > self.max_frame_size {
+            return Err(FrameError::TooLarge(size));
+        }
+
+        if self.buffer.len() < FRAME_HEADER_SIZE + size {
+            return Ok(None);
+        }
+
+        let message = self.buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + size].to_vec();
+        self.buffer.drain(..FRAME_HEADER_SIZE + size);
+
+        Ok(Some(Frame { id, message }))
+    }
+
+    /// Encodes a message into a frame, ready to be written to the stream.
+    pub fn encode(&self, message: &[u8]) -> Result<Vec<u8>, FrameError> {
+        if message.len() > self.max_frame_size {
+            return Err(FrameError::TooLarge(message.len()));
+        }
+
+        let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + message.len());
+        buf.extend_from_slice(&FRAME_ID.to_be_bytes());
+        buf.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        buf.extend_from_slice(message);
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameCodec, FrameError};
+    use crate::net::tcp::{FRAME_HEADER_SIZE, FRAME_ID};
+
+    #[test]
+    fn decode_byte_at_a_time() {
+        let mut codec = FrameCodec::new();
+        let frame = codec.encode(b"hello").unwrap();
+
+        for byte in &frame[..frame.len() - 1] {
+            codec.extend(&[*byte]);
+            assert!(codec.decode().unwrap().is_none());
+        }
+
+        codec.extend(&frame[frame.len() - 1..]);
+        let decoded = codec.decode().unwrap().unwrap();
+        assert_eq!(decoded.id, FRAME_ID);
+        assert_eq!(decoded.message, b"hello");
+        assert_eq!(codec.buffered(), 0);
+    }
+
+    #[test]
+    fn decode_many_in_one_read() {
+        let mut codec = FrameCodec::new();
+        let mut buf = Vec::new();
+        buf.extend(codec.encode(b"one").unwrap());
+        buf.extend(codec.encode(b"").unwrap());
+        buf.extend(codec.encode(b"three").unwrap());
+        // the start of a fourth frame.
+        buf.extend(&codec.encode(b"four").unwrap()[..3]);
+
+        codec.extend(&buf);
+        assert_eq!(codec.decode().unwrap().unwrap().message, b"one");
+        assert_eq!(codec.decode().unwrap().unwrap().message, b"");
+        assert_eq!(codec.decode().unwrap().unwrap().message, b"three");
+        assert!(codec.decode().unwrap().is_none());
+        assert_eq!(codec.buffered(), 3);
+    }
+
+    #[test]
+    fn too_large_is_refused_from_the_header() {
+        let mut codec = FrameCodec::new().with_max_frame_size(16);
+        assert_eq!(codec.encode(&[0; 17]), Err(FrameError::TooLarge(17)));
+
+        // only the header has arrived, the message is never waited for.
+        codec.extend(&FRAME_ID.to_be_bytes());
+        codec.extend(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            codec.decode().unwrap_err(),
+            FrameError::TooLarge(u32::MAX as usize)
+        );
+        assert_eq!(codec.buffered(), FRAME_HEADER_SIZE);
+    }
+
+    #[test]
+    fn invalid_id() {
+        let mut codec = FrameCodec::new();
+        let mut frame = codec.encode(b"hello").unwrap();
+        frame[..2].copy_from_slice(&(FRAME_ID + 1).to_be_bytes());
+
+        codec.extend(&frame);
+        assert_eq!(
+            codec.decode().unwrap_err(),
+            FrameError::InvalidId(FRAME_ID + 1)
+        );
+    }
+}
@@ -4,8 +4,17 @@ use binary_util::{
 };
 use std::io;
 
+/// Splits the tcp stream into frames.
+pub mod codec;
+
 pub const PROTOCOL_VERSION: u16 = 1;
+/// Every frame starts with this id.
+pub const FRAME_ID: u16 = 54;
+/// 2 (id) + 4 (message length)
+pub const FRAME_HEADER_SIZE: usize = 2 + 4;
 
+/// A single message on the tcp stream.
+/// Frames are the id, followed by the length of the message as a big endian `u32`, then the message.
 #[derive(Debug, Clone)]
 pub struct Frame {
     pub id: u16,
@@ -15,7 +24,7 @@ pub struct Frame {
 impl Frame {
     pub fn new(message: Vec<u8>) -> Self {
         Self {
-            id: 54,
+            id: FRAME_ID,
             message: message,
         }
     }
@@ -25,11 +34,22 @@ impl Reader<Frame> for Frame {
     fn read(buf: &mut binary_util::ByteReader) -> Result<Frame, std::io::Error> {
         let id = buf.read_u16()?;
 
-        if id != 54 {
+        if id != FRAME_ID {
             return Err(io::Error::new(io::ErrorKind::Other, "Invalid frame ID"));
         }
 
-        let message = buf.read_type::<Vec<u8>>()?;
+        let size = buf.read_u32()? as usize;
+
+        // don't trust the length until we know the bytes are there.
+        if size > buf.as_slice().len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Frame is incomplete",
+            ));
+        }
+
+        let mut message = vec![0u8; size];
+        buf.read(&mut message)?;
 
         Ok(Frame { id, message })
     }
@@ -38,7 +58,8 @@ impl Reader<Frame> for Frame {
 impl Writer for Frame {
     fn write(&self, buf: &mut binary_util::ByteWriter) -> Result<(), std::io::Error> {
         buf.write_u16(self.id)?;
-        buf.write_type(&self.message)?;
+        buf.write_u32(self.message.len() as u32)?;
+        buf.write(&self.message)?;
 
         Ok(())
     }
@@ -66,11 +87,16 @@ pub struct Connect {
     /// by default this is 1024
     /// > CURRENTLY IGNORED
     pub max_size: u16,
+    /// The heartbeat interval the client would like, in seconds.
+    /// 0 uses the server's interval, the server may choose a longer interval than requested.
+    pub heartbeat_interval: u16,
 }
 
 #[derive(Debug, Clone, BinaryIo)]
 pub struct Hello {
     pub timestamp: Option<u64>,
+    /// The negotiated heartbeat interval, in seconds.
+    /// Both sides send a heartbeat every interval, a side that misses too many is disconnected.
     pub interval: u16,
 }
 
@@ -82,6 +108,7 @@ pub enum Disconnect {
     InvalidProtocol,
     SelfInitiated,
     Unknown,
+    LimitExceeded,
 }
 
 impl std::fmt::Display for Disconnect {
@@ -92,6 +119,7 @@ impl std::fmt::Display for Disconnect {
             Disconnect::InvalidProtocol => write!(f, "Invalid protocol"),
             Disconnect::SelfInitiated => write!(f, "Self initiated"),
             Disconnect::Unknown => write!(f, "Unknown"),
+            Disconnect::LimitExceeded => write!(f, "Limit exceeded"),
         }
     }
 }
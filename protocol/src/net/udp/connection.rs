@@ -0,0 +1,779 @@
+use std::{collections::VecDeque, time::Duration};
+
+use binary_util::interfaces::{Reader, Writer};
+
+use crate::{
+    net::udp::{
+        crypto::{CryptoError, KeyExchange, OpeningKey, Role},
+        proto::{
+            offline::{
+                self, ConnectRequest, ConnectResponse, Cookie, KeyShare, SkylineHeader,
+            },
+            online::{
+                ack::{AckVariant, Acknowledgement},
+                sealed::{Sealed, SealedKind},
+                OnlinePackets, Ping, Pong,
+            },
+            Packets, IP_UDP_HEADER_SIZE, MAX_MTU_SIZE, MIN_MTU_SIZE,
+        },
+        queue::{
+            recv::{RecvQueue, RecvQueueError},
+            rtt::DEFAULT_MAX_RETRIES,
+            send::{
+                Receipt, SendOrdering, SendPriority, SendQueue, SendQueueError, SendQueueStats,
+            },
+            split::SplitLimits,
+            window::Window,
+        },
+    },
+    skyline::{connection::DisconnectReason, SkylinePacket},
+    util::serial,
+};
+
+/// How often the connection flushes it's send queue and acknowledgements, in milliseconds.
+pub const TICK_INTERVAL: u64 = 50;
+/// How often a client resends it's `ConnectRequest` until it gets a response, in milliseconds.
+pub const HANDSHAKE_RETRY_INTERVAL: u64 = 500;
+/// How long a client waits for the handshake to complete, in milliseconds.
+pub const HANDSHAKE_TIMEOUT: u64 = 10_000;
+/// The sizes a client probes the path mtu with during the handshake, from largest to smallest.
+/// Sizes above the requested mtu are skipped.
+pub const MTU_PROBE_SIZES: [u16; 5] = [MAX_MTU_SIZE, 1280, 1200, 1024, MIN_MTU_SIZE];
+/// How many times a probe is sent without a reply before the next smaller size is tried.
+pub const MTU_PROBE_ATTEMPTS: u8 = 2;
+/// The space reserved in an acknowledgement datagram for the ip, udp and packet headers.
+const ACK_OVERHEAD: usize = 64;
+
+/// Options for a single `Connection`.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionOptions {
+    /// The amount of times a packet is resent before the connection is considered lost.
+    pub max_retries: u8,
+    /// Limits on partial splits, a peer that exceeds these is disconnected.
+    pub split_limits: SplitLimits,
+    /// How often a keepalive ping is sent, this also measures the round trip time.
+    pub keepalive_interval: Duration,
+    /// How long the peer can be silent before the connection is `TimingOut`.
+    pub timeout: Duration,
+    /// How long the peer can be silent before the connection is closed.
+    pub hard_timeout: Duration,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            split_limits: SplitLimits::default(),
+            keepalive_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10),
+            hard_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The handshake has not completed yet.
+    Connecting,
+    Connected,
+    /// Nothing has been recieved from the peer for a while.
+    TimingOut,
+    Disconnected,
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection was closed with `Connection::close`.
+    Local,
+    /// Nothing was recieved from the peer within the hard timeout.
+    TimedOut,
+    /// A packet was resent too many times without being acknowledged.
+    RetryLimitExceeded,
+    /// The peer exceeded a resource limit, IE: too many partial splits.
+    LimitExceeded,
+    /// The handshake did not complete, or the peer's key share was invalid.
+    HandshakeFailed,
+}
+
+/// Something that happened on a connection, returned by `Connection::poll_event`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The handshake is complete, packets can be sent.
+    Connected,
+    /// A complete payload was recieved from the peer.
+    Payload(Vec<u8>),
+    /// A packet sent with `Connection::send_tracked` was delivered, or dropped.
+    /// Every pending receipt fails before the connection is `Disconnected`.
+    Receipt(Receipt),
+    /// The connection is closed, this is always the last event.
+    Disconnected(CloseReason),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The handshake has not completed, or the connection is closed.
+    NotConnected,
+    Send(SendQueueError),
+}
+
+/// Everything a `Connection` produced after being driven, see `Connection::drive`.
+#[derive(Debug, Default)]
+pub struct Output {
+    /// Datagrams to send to the peer, in order.
+    pub transmits: Vec<Vec<u8>>,
+    pub events: Vec<Event>,
+    /// When the connection should be driven again if nothing is recieved, in milliseconds.
+    /// This is `None` once the connection is closed.
+    pub next_timeout: Option<u64>,
+}
+
+/// The client's side of the handshake, until the server responds.
+struct ClientHandshake {
+    exchange: Option<KeyExchange>,
+    /// The requested mtu.
+    mtu: u16,
+    /// The sizes the `ConnectRequest` is padded to, from largest to smallest.
+    probes: Vec<u16>,
+    /// The index of the current probe.
+    probe: usize,
+    /// How many times the current probe was sent without a reply.
+    attempts: u8,
+    cookie: Option<Cookie>,
+    started: u64,
+    last_sent: u64,
+}
+
+/// A single UDP connection, without any IO.
+///
+/// The connection is driven by the caller: datagrams from the peer are passed to
+/// `handle_datagram`, and `handle_timeout` is called once `poll_timeout` has passed.
+/// Datagrams for the peer are taken with `poll_transmit` and events with `poll_event`.
+/// All times are in milliseconds, from any clock, as long as it is the same clock for
+/// the lifetime of the connection.
+///
+/// This makes the protocol usable from any runtime, and testable without sockets.
+pub struct Connection {
+    role: Role,
+    state: ConnectionState,
+    options: ConnectionOptions,
+    /// The mtu that was negotiated during the handshake.
+    mtu: u16,
+    /// Our public key from the handshake, this is resent if the peer
+    /// didn't recieve our `ConnectResponse`.
+    key_share: KeyShare,
+    /// Issued by the server during the handshake, and sent with every sealed packet.
+    connection_id: u64,
+    handshake: Option<ClientHandshake>,
+    opening: Option<OpeningKey>,
+    send_q: SendQueue,
+    recv_q: RecvQueue,
+    /// Offline packets waiting to be sent, these are sent before the send queue.
+    outgoing: VecDeque<Vec<u8>>,
+    events: VecDeque<Event>,
+    /// When the last authenticated packet was recieved.
+    last_recv: u64,
+    /// The newest sealed datagram and control sequence that was authenticated.
+    newest_datagram: Option<u32>,
+    newest_control: Option<u32>,
+    /// Sealed control packets that were recieved, so a replayed packet is dropped.
+    /// Datagrams are checked by the recieve queue's window instead.
+    control_window: Window,
+    next_ping: u64,
+    next_tick: u64,
+}
+
+impl Connection {
+    /// Starts a connection to a server, the `ConnectRequest` is queued immediately.
+    pub fn connect(mtu: u16, options: ConnectionOptions, now: u64) -> Self {
+        let mtu = mtu.clamp(MIN_MTU_SIZE, MAX_MTU_SIZE);
+        let exchange = KeyExchange::new();
+        let key_share = exchange.key_share();
+
+        let mut conn = Self::new(Role::Client, mtu, key_share, options, now);
+        conn.state = ConnectionState::Connecting;
+
+        let mut probes = vec![mtu];
+        probes.extend(MTU_PROBE_SIZES.iter().copied().filter(|size| *size < mtu));
+
+        conn.handshake = Some(ClientHandshake {
+            exchange: Some(exchange),
+            mtu,
+            probes,
+            probe: 0,
+            attempts: 0,
+            cookie: None,
+            started: now,
+            last_sent: now,
+        });
+        conn.send_connect_request(now);
+        conn
+    }
+
+    /// Accepts a client's `ConnectRequest`, the cookie must already be verified.
+    /// The `ConnectResponse` is queued immediately, and the connection is `Connected`.
+    ///
+    /// `len` is the size of the datagram the request arrived in, without the ip and udp headers.
+    /// The request is a path mtu probe, so the mtu is never larger than what arrived.
+    pub fn accept(
+        request: &ConnectRequest,
+        len: usize,
+        options: ConnectionOptions,
+        now: u64,
+    ) -> Result<Self, CryptoError> {
+        let arrived = (len + IP_UDP_HEADER_SIZE as usize).min(u16::MAX as usize) as u16;
+        let mtu = request.mtu.min(arrived).clamp(MIN_MTU_SIZE, MAX_MTU_SIZE);
+        let exchange = KeyExchange::new();
+        let key_share = exchange.key_share();
+        let keys = exchange.complete(&request.key_share, Role::Server)?;
+
+        let mut conn = Self::new(Role::Server, mtu, key_share, options, now);
+        conn.connection_id = rand::random();
+        conn.send_q = conn
+            .send_q
+            .with_sealing_key(keys.sealing.with_connection_id(conn.connection_id));
+        conn.opening = Some(keys.opening);
+        conn.state = ConnectionState::Connected;
+        conn.send_connect_response(request.client_time, now);
+        conn.events.push_back(Event::Connected);
+
+        Ok(conn)
+    }
+
+    fn new(
+        role: Role,
+        mtu: u16,
+        key_share: KeyShare,
+        options: ConnectionOptions,
+        now: u64,
+    ) -> Self {
+        Self {
+            role,
+            state: ConnectionState::Connecting,
+            options,
+            mtu,
+            key_share,
+            connection_id: 0,
+            handshake: None,
+            opening: None,
+            send_q: SendQueue::new(mtu).with_max_retries(options.max_retries),
+            recv_q: RecvQueue::with_limits(options.split_limits),
+            outgoing: VecDeque::new(),
+            events: VecDeque::new(),
+            last_recv: now,
+            newest_datagram: None,
+            newest_control: None,
+            control_window: Window::new(),
+            next_ping: now,
+            next_tick: now + TICK_INTERVAL,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// The mtu that was negotiated during the handshake.
+    pub fn mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Our public key from the handshake.
+    pub fn key_share(&self) -> KeyShare {
+        self.key_share
+    }
+
+    /// The id the server issued for this connection, this is `0` until the handshake is complete.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Returns the congestion window and round trip time of this connection, for diagnostics.
+    pub fn stats(&self) -> SendQueueStats {
+        self.send_q.stats()
+    }
+
+    fn is_open(&self) -> bool {
+        match self.state {
+            ConnectionState::Connected | ConnectionState::TimingOut => true,
+            _ => false,
+        }
+    }
+
+    /// Queues a payload to be sent to the peer.
+    pub fn send(
+        &mut self,
+        payload: &[u8],
+        priority: SendPriority,
+        now: u64,
+    ) -> Result<(), ConnectionError> {
+        self.send_with(payload, priority, SendOrdering::None, None, now)
+    }
+
+    /// Queues a payload that is delivered with the given `ordering` on `channel`.
+    pub fn send_with(
+        &mut self,
+        payload: &[u8],
+        priority: SendPriority,
+        ordering: SendOrdering,
+        channel: Option<u16>,
+        now: u64,
+    ) -> Result<(), ConnectionError> {
+        if !self.is_open() {
+            return Err(ConnectionError::NotConnected);
+        }
+
+        self.send_q
+            .insert_with(payload, priority, ordering, channel, now)
+            .map_err(ConnectionError::Send)
+    }
+
+    /// Queues a reliable payload like `send_with`, and returns it's receipt id.
+    /// An `Event::Receipt` with this id is emitted once the peer acknowledges the payload,
+    /// or when it is dropped.
+    pub fn send_tracked(
+        &mut self,
+        payload: &[u8],
+        priority: SendPriority,
+        ordering: SendOrdering,
+        channel: Option<u16>,
+        now: u64,
+    ) -> Result<u32, ConnectionError> {
+        if !self.is_open() {
+            return Err(ConnectionError::NotConnected);
+        }
+
+        self.send_q
+            .insert_tracked(payload, priority, ordering, channel, now)
+            .map_err(ConnectionError::Send)
+    }
+
+    /// Closes the connection, notifying the peer.
+    /// Anything still queued is discarded.
+    pub fn close(&mut self, reason: DisconnectReason, now: u64) {
+        self.close_with(reason, CloseReason::Local, now);
+    }
+
+    fn close_with(&mut self, reason: DisconnectReason, close: CloseReason, now: u64) {
+        if self.state == ConnectionState::Disconnected {
+            return;
+        }
+
+        if self.is_open() {
+            // notify the peer with both a skyline `Disconnect` and an offline `Disconnect`.
+            let offline_reason = match reason {
+                DisconnectReason::LimitExceeded => offline::DisconnectReason::LimitExceeded,
+                DisconnectReason::TimedOut => offline::DisconnectReason::TimedOut,
+                _ => offline::DisconnectReason::Unknown,
+            };
+
+            let disconnect =
+                SkylinePacket::Disconnect(crate::skyline::connection::Disconnect { reason });
+
+            if let Ok(buf) = disconnect.write_to_bytes() {
+                let _ = self
+                    .send_q
+                    .insert(buf.as_slice(), SendPriority::Immediate, None, now);
+            }
+
+            let disconnect = Packets::Disconnect(offline::Disconnect {
+                reason: offline_reason,
+            });
+
+            if let Ok(buf) = disconnect.write_to_bytes() {
+                self.send_q.send_raw(buf.as_slice());
+            }
+        }
+
+        self.handshake = None;
+        self.disconnected(close);
+    }
+
+    /// Marks the connection as closed, failing every pending receipt.
+    fn disconnected(&mut self, reason: CloseReason) {
+        self.state = ConnectionState::Disconnected;
+        self.send_q.fail_receipts();
+        self.flush_receipts();
+        self.events.push_back(Event::Disconnected(reason));
+    }
+
+    fn flush_receipts(&mut self) {
+        while let Some(receipt) = self.send_q.poll_receipt() {
+            self.events.push_back(Event::Receipt(receipt));
+        }
+    }
+
+    /// Processes a single datagram from the peer.
+    ///
+    /// Returns `true` if the datagram was authenticated, and is newer than anything authenticated
+    /// before it. Only these datagrams may move the connection to a new address, a replayed
+    /// datagram is never newer.
+    pub fn handle_datagram(&mut self, buf: &[u8], now: u64) -> bool {
+        if self.state == ConnectionState::Disconnected {
+            return false;
+        }
+
+        let packet = match Packets::read_from_slice(buf) {
+            Ok(packet) => packet,
+            Err(_) => return false,
+        };
+
+        match packet {
+            Packets::OnlinePacket(OnlinePackets::Sealed(sealed)) => {
+                let packet = match self.open(&sealed) {
+                    Some(packet) => packet,
+                    // this packet failed authentication.
+                    None => return false,
+                };
+
+                // a replayed packet is authenticated too, it must not be handled twice.
+                if sealed.kind == SealedKind::Control
+                    && !self.control_window.insert_sliding(sealed.sequence)
+                {
+                    return false;
+                }
+
+                if !self.handle_online(packet, now) {
+                    return false;
+                }
+
+                let newest = match sealed.kind {
+                    SealedKind::Datagram => &mut self.newest_datagram,
+                    SealedKind::Control => &mut self.newest_control,
+                };
+
+                let is_newest = newest.map_or(true, |seq| serial::gt(sealed.sequence, seq));
+                if is_newest {
+                    *newest = Some(sealed.sequence);
+                }
+
+                // only fresh authenticated packets keep the connection alive, otherwise anyone
+                // could keep a dead connection in memory by spoofing it's address, or replaying
+                // it's packets.
+                self.last_recv = now;
+                if self.state == ConnectionState::TimingOut {
+                    self.state = ConnectionState::Connected;
+                }
+
+                return is_newest;
+            }
+            Packets::ConnectRetry(retry) => {
+                if let Some(ref mut handshake) = self.handshake {
+                    // the probe made it to the server, keep using this size.
+                    handshake.cookie = Some(retry.cookie);
+                    handshake.attempts = 0;
+                    self.send_connect_request(now);
+                }
+            }
+            Packets::ConnectResponse(response) => {
+                self.complete_handshake(response, now);
+            }
+            Packets::ConnectRequest(request) => {
+                // the client didn't recieve our response, send it again.
+                if self.role == Role::Server && self.is_open() {
+                    self.send_connect_response(request.client_time, now);
+                }
+            }
+            // once the handshake is complete, only sealed packets are accepted.
+            // an offline `Disconnect` can't be authenticated, the peer's skyline
+            // `Disconnect` is delivered sealed instead.
+            _ => {}
+        }
+
+        false
+    }
+
+    /// Drives the connection's timers, this should be called once `poll_timeout` has passed.
+    pub fn handle_timeout(&mut self, now: u64) {
+        match self.state {
+            ConnectionState::Disconnected => return,
+            ConnectionState::Connecting => {
+                let handshake = match self.handshake {
+                    Some(ref mut handshake) => handshake,
+                    None => return,
+                };
+
+                if now >= handshake.started + HANDSHAKE_TIMEOUT {
+                    self.close_with(DisconnectReason::Closed, CloseReason::HandshakeFailed, now);
+                } else if now >= handshake.last_sent + HANDSHAKE_RETRY_INTERVAL {
+                    // nothing came back, the probe may be too large for the path.
+                    handshake.attempts += 1;
+                    if handshake.attempts >= MTU_PROBE_ATTEMPTS
+                        && handshake.probe + 1 < handshake.probes.len()
+                    {
+                        handshake.probe += 1;
+                        handshake.attempts = 0;
+                    }
+
+                    self.send_connect_request(now);
+                }
+
+                return;
+            }
+            _ => {}
+        }
+
+        let silence = now.saturating_sub(self.last_recv);
+
+        if silence >= self.options.hard_timeout.as_millis() as u64 {
+            self.close_with(DisconnectReason::TimedOut, CloseReason::TimedOut, now);
+            return;
+        }
+
+        if silence >= self.options.timeout.as_millis() as u64 {
+            self.state = ConnectionState::TimingOut;
+        }
+
+        if now >= self.next_ping {
+            self.next_ping = now + self.options.keepalive_interval.as_millis() as u64;
+            let _ = self
+                .send_q
+                .send_online(OnlinePackets::Ping(Ping { send: now }), now);
+        }
+
+        if now >= self.next_tick {
+            self.next_tick = now + TICK_INTERVAL;
+            self.flush_acks(now);
+            self.recv_q.expire_splits(now);
+
+            if let Err(SendQueueError::RetryLimitExceeded) = self.send_q.update(now) {
+                // the peer is unreachable, there's no point in notifying it.
+                self.disconnected(CloseReason::RetryLimitExceeded);
+            }
+        }
+    }
+
+    /// When `handle_timeout` should be called next, `None` once the connection is closed.
+    pub fn poll_timeout(&self) -> Option<u64> {
+        match self.state {
+            ConnectionState::Disconnected => None,
+            ConnectionState::Connecting => self.handshake.as_ref().map(|handshake| {
+                (handshake.last_sent + HANDSHAKE_RETRY_INTERVAL)
+                    .min(handshake.started + HANDSHAKE_TIMEOUT)
+            }),
+            _ => {
+                let hard_timeout = self.last_recv + self.options.hard_timeout.as_millis() as u64;
+                Some(self.next_tick.min(self.next_ping).min(hard_timeout))
+            }
+        }
+    }
+
+    /// Takes the next datagram that should be sent to the peer.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outgoing
+            .pop_front()
+            .or_else(|| self.send_q.poll_transmit())
+    }
+
+    /// Takes the next event from the connection.
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    /// Processes an optional datagram and the timers at `now`, then collects everything
+    /// the connection produced.
+    pub fn drive(&mut self, now: u64, datagram: Option<&[u8]>) -> Output {
+        if let Some(buf) = datagram {
+            self.handle_datagram(buf, now);
+        }
+
+        if self.poll_timeout().map_or(false, |timeout| timeout <= now) {
+            self.handle_timeout(now);
+        }
+
+        let mut output = Output::default();
+
+        while let Some(buf) = self.poll_transmit() {
+            output.transmits.push(buf);
+        }
+
+        while let Some(event) = self.poll_event() {
+            output.events.push(event);
+        }
+
+        output.next_timeout = self.poll_timeout();
+        output
+    }
+
+    /// Handles an opened packet, returns false if it was a replayed datagram, or the
+    /// connection was closed because of it.
+    fn handle_online(&mut self, packet: OnlinePackets, now: u64) -> bool {
+        match packet {
+            OnlinePackets::Datagram(datagram) => {
+                if let Err(e) = self.recv_q.insert(datagram, now) {
+                    if e.is_fatal() {
+                        self.close_with(
+                            DisconnectReason::LimitExceeded,
+                            CloseReason::LimitExceeded,
+                            now,
+                        );
+                        return false;
+                    }
+
+                    if e == RecvQueueError::OldSequence {
+                        return false;
+                    }
+                }
+
+                // acknowledgements are sent by the tick.
+                for payload in self.recv_q.flush() {
+                    self.events.push_back(Event::Payload(payload));
+                }
+            }
+            OnlinePackets::Ack(variant) => match variant {
+                AckVariant::Ack(ack) => {
+                    self.send_q.ack(ack, now);
+                    self.flush_receipts();
+                }
+                AckVariant::Nack(nack) => {
+                    // the missing sets are requeued, they will be packed into new datagrams
+                    // on the next update.
+                    self.send_q.nack(nack, now);
+                }
+            },
+            OnlinePackets::Ping(ping) => {
+                let pong = OnlinePackets::Pong(Pong {
+                    send: ping.send,
+                    recv: now,
+                });
+
+                let _ = self.send_q.send_online(pong, now);
+            }
+            OnlinePackets::Pong(pong) => {
+                self.send_q.update_rtt(now.saturating_sub(pong.send));
+            }
+            OnlinePackets::Sealed(_) => {}
+        }
+
+        true
+    }
+
+    /// Decrypts a sealed packet, returning `None` if it can not be authenticated.
+    /// A sealed datagram must carry the same sequence it was sealed with, so the recieve
+    /// window can reject replays.
+    fn open(&mut self, sealed: &Sealed) -> Option<OnlinePackets> {
+        if sealed.connection_id != self.connection_id {
+            return None;
+        }
+
+        let plaintext = self.opening.as_mut()?.open(sealed).ok()?;
+
+        match OnlinePackets::read_from_slice(&plaintext).ok()? {
+            OnlinePackets::Sealed(_) => None,
+            OnlinePackets::Datagram(datagram) => {
+                if sealed.kind != SealedKind::Datagram || datagram.sequence != sealed.sequence {
+                    return None;
+                }
+
+                Some(OnlinePackets::Datagram(datagram))
+            }
+            packet => {
+                if sealed.kind != SealedKind::Control {
+                    return None;
+                }
+
+                Some(packet)
+            }
+        }
+    }
+
+    /// Sends an `Ack` for every datagram recieved since the last tick, and a `Nack`
+    /// for every datagram we are still missing.
+    /// Large acknowledgements are split so each one fits in a single datagram.
+    fn flush_acks(&mut self, now: u64) {
+        let acks = Acknowledgement::from_seqs(self.recv_q.ack_flush());
+        let nacks = Acknowledgement::from_seqs(self.recv_q.nack_queue());
+        let max_size = (self.mtu as usize).saturating_sub(ACK_OVERHEAD);
+
+        for ack in acks.into_chunks(max_size) {
+            let _ = self
+                .send_q
+                .send_online(OnlinePackets::Ack(ack.to_ack()), now);
+        }
+
+        for nack in nacks.into_chunks(max_size) {
+            let _ = self
+                .send_q
+                .send_online(OnlinePackets::Ack(nack.to_nack()), now);
+        }
+    }
+
+    fn send_connect_request(&mut self, now: u64) {
+        let handshake = match self.handshake {
+            Some(ref mut handshake) => handshake,
+            None => return,
+        };
+
+        handshake.last_sent = now;
+        let size = handshake.probes[handshake.probe];
+
+        let request = Packets::ConnectRequest(ConnectRequest {
+            header: SkylineHeader {},
+            mtu: size,
+            client_time: now / 1000,
+            key_share: self.key_share,
+            cookie: handshake.cookie,
+        });
+
+        if let Ok(buf) = request.write_to_bytes() {
+            // the request is padded to the probe size, the server replies with the size that arrived.
+            let mut buf = buf.as_slice().to_vec();
+            let padded = (size - IP_UDP_HEADER_SIZE) as usize;
+            if buf.len() < padded {
+                buf.resize(padded, 0);
+            }
+
+            self.outgoing.push_back(buf);
+        }
+    }
+
+    fn send_connect_response(&mut self, client_time: u64, now: u64) {
+        let response = Packets::ConnectResponse(ConnectResponse {
+            header: SkylineHeader {},
+            mtu: self.mtu,
+            server_time: now / 1000,
+            client_time,
+            key_share: self.key_share,
+            connection_id: self.connection_id,
+        });
+
+        if let Ok(buf) = response.write_to_bytes() {
+            self.outgoing.push_back(buf.as_slice().to_vec());
+        }
+    }
+
+    fn complete_handshake(&mut self, response: ConnectResponse, now: u64) {
+        let exchange = match self.handshake.as_mut().and_then(|h| h.exchange.take()) {
+            Some(exchange) => exchange,
+            // we are not waiting for a response, or already have one.
+            None => return,
+        };
+
+        let keys = match exchange.complete(&response.key_share, Role::Client) {
+            Ok(keys) => keys,
+            Err(_) => {
+                self.close_with(DisconnectReason::Closed, CloseReason::HandshakeFailed, now);
+                return;
+            }
+        };
+
+        // the server may have lowered our mtu, but it can never raise it.
+        let requested = self.handshake.as_ref().map_or(self.mtu, |h| h.mtu);
+        self.mtu = response.mtu.min(requested).clamp(MIN_MTU_SIZE, MAX_MTU_SIZE);
+        self.connection_id = response.connection_id;
+        self.send_q = SendQueue::new(self.mtu)
+            .with_max_retries(self.options.max_retries)
+            .with_sealing_key(keys.sealing.with_connection_id(self.connection_id));
+        self.opening = Some(keys.opening);
+        self.handshake = None;
+        self.state = ConnectionState::Connected;
+        self.last_recv = now;
+        self.next_ping = now;
+        self.next_tick = now + TICK_INTERVAL;
+        self.events.push_back(Event::Connected);
+    }
+}
@@ -0,0 +1,75 @@
+use std::net::SocketAddr;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::net::udp::proto::offline::{Cookie, COOKIE_MAC_SIZE};
+use crate::util::current_epoch;
+
+/// How long a cookie is valid for after it is issued, in seconds.
+pub const COOKIE_LIFETIME: u64 = 10;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Issues and verifies stateless handshake cookies.
+///
+/// A cookie is an HMAC of the client's address and the time it was issued, signed with a
+/// secret only the server knows. The server can verify a cookie without remembering it,
+/// so no state is created for a client until it has proven it owns it's address.
+#[derive(Clone)]
+pub struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl CookieJar {
+    /// Creates a cookie jar with a random secret.
+    pub fn new() -> Self {
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self { secret }
+    }
+
+    /// Creates a cookie jar with the given secret.
+    /// Every server sharing a secret will accept each others cookies.
+    pub fn with_secret(secret: [u8; 32]) -> Self {
+        Self { secret }
+    }
+
+    /// Issues a new cookie for `addr`.
+    pub fn issue(&self, addr: &SocketAddr) -> Cookie {
+        let timestamp = current_epoch();
+        let mut mac = [0u8; COOKIE_MAC_SIZE];
+        mac.copy_from_slice(&self.sign(addr, timestamp).finalize().into_bytes()[..COOKIE_MAC_SIZE]);
+
+        Cookie { timestamp, mac }
+    }
+
+    /// Whether `cookie` was issued by this jar for `addr`, and has not expired.
+    pub fn verify(&self, addr: &SocketAddr, cookie: &Cookie) -> bool {
+        let now = current_epoch();
+
+        // allow a second of clock drift between servers sharing a secret.
+        if cookie.timestamp > now + 1 || cookie.timestamp + COOKIE_LIFETIME < now {
+            return false;
+        }
+
+        self.sign(addr, cookie.timestamp)
+            .verify_truncated_left(&cookie.mac)
+            .is_ok()
+    }
+
+    fn sign(&self, addr: &SocketAddr, timestamp: u64) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+
+        match addr {
+            SocketAddr::V4(addr) => mac.update(&addr.ip().octets()),
+            SocketAddr::V6(addr) => mac.update(&addr.ip().octets()),
+        }
+
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&timestamp.to_be_bytes());
+        mac
+    }
+}
@@ -0,0 +1,269 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::net::udp::proto::{
+    offline::KeyShare,
+    online::sealed::{Sealed, SealedKind},
+};
+
+/// The amount of packets sealed with a single key before it is rotated.
+/// This must be lower than `u32::MAX`, so a sequence is never reused with the same key.
+pub const KEY_ROTATION_PACKETS: u32 = 1 << 24;
+/// How long a key is used before it is rotated, in milliseconds.
+pub const KEY_ROTATION_INTERVAL: u64 = 10 * 60 * 1000;
+
+const HANDSHAKE_SALT: &[u8] = b"skyline udp 1.0.0";
+const CLIENT_LABEL: &[u8] = b"skyline client key";
+const SERVER_LABEL: &[u8] = b"skyline server key";
+const UPDATE_LABEL: &[u8] = b"skyline key update";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// The peer's key share is a low order point, and can not be used.
+    InvalidKeyShare,
+    /// The packet could not be encrypted.
+    SealFailed,
+    /// The packet could not be authenticated, it was tampered with or
+    /// was not sealed with our keys.
+    OpenFailed,
+}
+
+/// Which side of the handshake we are on.
+/// This decides which of the derived keys is used for sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// An ephemeral X25519 key exchange.
+/// A new exchange is created for every connection, so a compromised session key
+/// does not compromise any other session.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /// The public key to send to the peer.
+    pub fn key_share(&self) -> KeyShare {
+        KeyShare {
+            key: *self.public.as_bytes(),
+        }
+    }
+
+    /// Completes the exchange with the peer's key share, deriving a key for each direction.
+    pub fn complete(self, peer: &KeyShare, role: Role) -> Result<SessionKeys, CryptoError> {
+        let ours = self.key_share();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer.key));
+
+        if !shared.was_contributory() {
+            return Err(CryptoError::InvalidKeyShare);
+        }
+
+        // both sides must derive the keys from the same transcript.
+        let (client, server) = match role {
+            Role::Client => (ours.key, peer.key),
+            Role::Server => (peer.key, ours.key),
+        };
+
+        let hkdf = Hkdf::<Sha256>::new(Some(HANDSHAKE_SALT), shared.as_bytes());
+        let client_key = expand(&hkdf, &[CLIENT_LABEL, &client, &server]);
+        let server_key = expand(&hkdf, &[SERVER_LABEL, &client, &server]);
+
+        let (send, recv) = match role {
+            Role::Client => (client_key, server_key),
+            Role::Server => (server_key, client_key),
+        };
+
+        Ok(SessionKeys {
+            sealing: SealingKey::new(send),
+            opening: OpeningKey::new(recv),
+        })
+    }
+}
+
+/// The keys for a session, once the handshake is complete.
+pub struct SessionKeys {
+    pub sealing: SealingKey,
+    pub opening: OpeningKey,
+}
+
+/// Encrypts outgoing packets, rotating the key periodically.
+pub struct SealingKey {
+    key: [u8; 32],
+    phase: u8,
+    /// Sent and authenticated with every sealed packet.
+    connection_id: u64,
+    /// The amount of packets sealed with the current key.
+    sealed: u32,
+    /// When the current key was first used, in milliseconds.
+    created: Option<u64>,
+}
+
+impl SealingKey {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            phase: 0,
+            connection_id: 0,
+            sealed: 0,
+            created: None,
+        }
+    }
+
+    /// Sets the connection id that is sent with every sealed packet.
+    pub fn with_connection_id(mut self, connection_id: u64) -> Self {
+        self.connection_id = connection_id;
+        self
+    }
+
+    /// Encrypts `plaintext`, the `kind` and `sequence` must never be reused.
+    /// `now` is the current time in milliseconds, it decides when the key is rotated.
+    pub fn seal(
+        &mut self,
+        kind: SealedKind,
+        sequence: u32,
+        plaintext: &[u8],
+        now: u64,
+    ) -> Result<Sealed, CryptoError> {
+        let created = *self.created.get_or_insert(now);
+
+        if self.sealed >= KEY_ROTATION_PACKETS || created + KEY_ROTATION_INTERVAL <= now {
+            self.rotate(now);
+        }
+
+        let nonce = nonce(self.phase, kind, sequence);
+        let aad = aad(self.phase, kind, self.connection_id, sequence);
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&self.key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| CryptoError::SealFailed)?;
+
+        self.sealed += 1;
+
+        Ok(Sealed {
+            phase: self.phase,
+            kind,
+            connection_id: self.connection_id,
+            sequence,
+            ciphertext,
+        })
+    }
+
+    fn rotate(&mut self, now: u64) {
+        self.key = next_key(&self.key);
+        self.phase ^= 1;
+        self.sealed = 0;
+        self.created = Some(now);
+    }
+}
+
+/// Decrypts incoming packets, following the peer's key rotations.
+pub struct OpeningKey {
+    key: [u8; 32],
+    phase: u8,
+    /// The key before the last rotation, this allows packets that were
+    /// reordered around a rotation to be opened.
+    previous: Option<[u8; 32]>,
+}
+
+impl OpeningKey {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            phase: 0,
+            previous: None,
+        }
+    }
+
+    /// Decrypts and authenticates a sealed packet.
+    ///
+    /// This does not protect against replays, the caller must check the
+    /// sequence against it's recieve window.
+    pub fn open(&mut self, sealed: &Sealed) -> Result<Vec<u8>, CryptoError> {
+        if sealed.phase == self.phase {
+            return open_with(&self.key, sealed);
+        }
+
+        // the peer has either rotated it's key, or this packet was sent before the last rotation.
+        let next = next_key(&self.key);
+
+        if let Ok(plaintext) = open_with(&next, sealed) {
+            self.previous = Some(self.key);
+            self.key = next;
+            self.phase = sealed.phase;
+            return Ok(plaintext);
+        }
+
+        match self.previous {
+            Some(ref previous) => open_with(previous, sealed),
+            None => Err(CryptoError::OpenFailed),
+        }
+    }
+}
+
+fn open_with(key: &[u8; 32], sealed: &Sealed) -> Result<Vec<u8>, CryptoError> {
+    let nonce = nonce(sealed.phase, sealed.kind, sealed.sequence);
+    let aad = aad(
+        sealed.phase,
+        sealed.kind,
+        sealed.connection_id,
+        sealed.sequence,
+    );
+
+    ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| CryptoError::OpenFailed)
+}
+
+fn expand(hkdf: &Hkdf<Sha256>, info: &[&[u8]]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    hkdf.expand_multi_info(info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    expand(&Hkdf::<Sha256>::new(None, key), &[UPDATE_LABEL])
+}
+
+fn nonce(phase: u8, kind: SealedKind, sequence: u32) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[0] = phase;
+    nonce[1] = kind as u8;
+    nonce[8..].copy_from_slice(&sequence.to_be_bytes());
+    nonce
+}
+
+fn aad(phase: u8, kind: SealedKind, connection_id: u64, sequence: u32) -> [u8; 14] {
+    let mut aad = [0u8; 14];
+    aad[0] = phase;
+    aad[1] = kind as u8;
+    aad[2..10].copy_from_slice(&connection_id.to_be_bytes());
+    aad[10..].copy_from_slice(&sequence.to_be_bytes());
+    aad
+}
@@ -1,3 +1,8 @@
+pub mod connection;
+pub mod cookie;
+pub mod crypto;
 pub mod proto;
 pub mod queue;
+pub mod sim;
+pub mod transport;
 pub mod types;
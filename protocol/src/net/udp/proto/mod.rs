@@ -5,9 +5,19 @@ use binary_util::BinaryIo;
 use self::{offline::OfflinePackets, online::OnlinePackets};
 
 pub const GUEST_UUID: &str = "00000000-0000-0000-0000-000000000000";
-// proto magic: IP + UDP + SKYLINE:1.0.0 + DatasetOverhead
+// proto magic: IP + UDP + SKYLINE:1.0.0 + DatasetOverhead + SealOverhead
 // datasetoverhead = 1 (flags) + 4 (seq) + 2 (sid) + 4 (stotal) + 4 (sindex) + 2 (oid) + 4 (oindex) + 4 (payload length) = 25
-pub const MAX_PROTO_OVERHEAD: u16 = 20 + 8 + 4 + 25;
+pub const MAX_PROTO_OVERHEAD: u16 = 20 + 8 + 4 + 25 + SEAL_OVERHEAD;
+// sealoverhead = 2 (online id) + 1 (phase) + 1 (kind) + 8 (connection id) + 4 (seq) + 4 (length) + 16 (tag) = 36
+pub const SEAL_OVERHEAD: u16 = 2 + 1 + 1 + 8 + 4 + 4 + 16;
+/// The size of the IP and UDP headers, these count towards the mtu.
+pub const IP_UDP_HEADER_SIZE: u16 = 20 + 8;
+/// The smallest mtu the server will negotiate with a client.
+/// This is the minimum datagram size every IPv4 host must be able to reassemble.
+pub const MIN_MTU_SIZE: u16 = 576;
+/// The largest mtu the server will negotiate with a client.
+/// Anything above this is likely to be fragmented on the open internet.
+pub const MAX_MTU_SIZE: u16 = 1400;
 
 /// Protocol designed to communicate with clients that are not connected
 /// to the server.
@@ -33,6 +43,7 @@ pub enum Packets {
     ConnectRequest(offline::ConnectRequest),
     ConnectResponse(offline::ConnectResponse),
     OnlinePacket(online::OnlinePackets),
+    ConnectRetry(offline::ConnectRetry),
 }
 
 impl Packets {
@@ -59,7 +70,7 @@ impl From<OfflinePackets> for Packets {
             OfflinePackets::Pong(packet) => Packets::Pong(packet),
             OfflinePackets::ConnectRequest(packet) => Packets::ConnectRequest(packet),
             OfflinePackets::ConnectResponse(packet) => Packets::ConnectResponse(packet),
-            _ => panic!("Invalid packet type!"),
+            OfflinePackets::ConnectRetry(packet) => Packets::ConnectRetry(packet),
         }
     }
 }
@@ -78,6 +89,7 @@ impl From<Packets> for OfflinePackets {
             Packets::Pong(packet) => OfflinePackets::Pong(packet),
             Packets::ConnectRequest(packet) => OfflinePackets::ConnectRequest(packet),
             Packets::ConnectResponse(packet) => OfflinePackets::ConnectResponse(packet),
+            Packets::ConnectRetry(packet) => OfflinePackets::ConnectRetry(packet),
             _ => panic!("Invalid packet type!"),
         }
     }
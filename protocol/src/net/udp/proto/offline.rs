@@ -18,7 +18,7 @@ impl Writer for SkylineHeader {
 
 impl Reader<SkylineHeader> for SkylineHeader {
     fn read(buf: &mut binary_util::ByteReader) -> Result<SkylineHeader, std::io::Error> {
-        let mut header = [0u8; 16];
+        let mut header = [0u8; SKYLINE_HEADER.len()];
         buf.read(&mut header)?;
         if header != *SKYLINE_HEADER {
             return Err(std::io::Error::new(
@@ -30,6 +30,59 @@ impl Reader<SkylineHeader> for SkylineHeader {
     }
 }
 
+/// The size of the truncated mac in a `Cookie`.
+pub const COOKIE_MAC_SIZE: usize = 16;
+
+/// A stateless cookie issued by the server in a `ConnectRetry`.
+/// The client must echo this cookie in it's next `ConnectRequest` to prove
+/// it owns the address it is sending from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cookie {
+    /// The epoch in seconds the cookie was issued at.
+    pub timestamp: u64,
+    /// A truncated HMAC of the client address and the timestamp.
+    pub mac: [u8; COOKIE_MAC_SIZE],
+}
+
+impl Writer for Cookie {
+    fn write(&self, buf: &mut binary_util::ByteWriter) -> Result<(), std::io::Error> {
+        buf.write_u64(self.timestamp)?;
+        buf.write(&self.mac)?;
+        Ok(())
+    }
+}
+
+impl Reader<Cookie> for Cookie {
+    fn read(buf: &mut binary_util::ByteReader) -> Result<Cookie, std::io::Error> {
+        let timestamp = buf.read_u64()?;
+        let mut mac = [0u8; COOKIE_MAC_SIZE];
+        buf.read(&mut mac)?;
+        Ok(Cookie { timestamp, mac })
+    }
+}
+
+/// An ephemeral X25519 public key, exchanged during the handshake
+/// to derive the session keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyShare {
+    pub key: [u8; 32],
+}
+
+impl Writer for KeyShare {
+    fn write(&self, buf: &mut binary_util::ByteWriter) -> Result<(), std::io::Error> {
+        buf.write(&self.key)?;
+        Ok(())
+    }
+}
+
+impl Reader<KeyShare> for KeyShare {
+    fn read(buf: &mut binary_util::ByteReader) -> Result<KeyShare, std::io::Error> {
+        let mut key = [0u8; 32];
+        buf.read(&mut key)?;
+        Ok(KeyShare { key })
+    }
+}
+
 #[derive(BinaryIo)]
 #[repr(u8)]
 pub enum OfflinePackets {
@@ -38,6 +91,8 @@ pub enum OfflinePackets {
     Pong(Pong),
     ConnectRequest(ConnectRequest),
     ConnectResponse(ConnectResponse),
+    /// `6` is used by online packets.
+    ConnectRetry(ConnectRetry) = 7,
 }
 
 /// This packet can be sent by the server or by the client.
@@ -55,10 +110,17 @@ pub struct Disconnect {
 #[repr(u8)]
 pub enum DisconnectReason {
     Unknown,
+    /// The peer exceeded a resource limit, IE: too many partial splits.
+    LimitExceeded,
+    /// Nothing was recieved from the peer for too long.
+    TimedOut,
 }
 
 /// An offline ping packet.
 /// This is used to measure the latency between the client and the server.
+///
+/// To recieve the server's status, pad the ping with trailing bytes so the
+/// status fits in the reply.
 #[derive(BinaryIo)]
 pub struct Ping {
     /// The time the packet was sent.
@@ -72,6 +134,36 @@ pub struct Pong {
     pub send: u64,
     /// The time the packet was recieved by the peer.
     pub recv: u64,
+    /// A description of the server, if it is public.
+    ///
+    /// The server never replies with more bytes than it recieved, so this is only
+    /// sent if the `Ping` was padded to fit it, IE: to the mtu.
+    pub status: Option<ServerStatus>,
+}
+
+/// Describes a server to peers that are not connected to it, so load balancers and
+/// server browsers can query a server without logging in.
+#[derive(Debug, Clone, BinaryIo)]
+pub struct ServerStatus {
+    /// The display name of the server.
+    pub name: String,
+    /// The skyline protocol version the server speaks.
+    pub protocol_version: u16,
+    /// The amount of peers currently connected.
+    pub peers: u16,
+    /// The maximum amount of peers, 0 means unlimited.
+    pub max_peers: u16,
+    /// The names of the channels anyone can subscribe to.
+    pub channels: Vec<String>,
+    /// The transports the server accepts connections on.
+    pub transports: Vec<Transport>,
+}
+
+#[derive(Debug, Clone, Copy, BinaryIo, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Transport {
+    Tcp,
+    Udp,
 }
 
 /// Attempts to establish a connection with the server.
@@ -79,10 +171,19 @@ pub struct Pong {
 #[derive(BinaryIo)]
 pub struct ConnectRequest {
     pub header: SkylineHeader,
-    /// The mtu you want to use as the client.
+    /// The mtu you want to use as the client, this is also the size of the probe.
+    ///
+    /// The request must be padded with trailing zeros so the datagram is `mtu` bytes
+    /// on the wire (including the ip and udp headers). The server negotiates the smaller
+    /// of this and the size that actually arrived.
     pub mtu: u16,
     /// The current epoch in seconds on the client.
     pub client_time: u64,
+    /// The client's ephemeral public key.
+    pub key_share: KeyShare,
+    /// The cookie from the server's `ConnectRetry`.
+    /// This is `None` on the first request.
+    pub cookie: Option<Cookie>,
 }
 
 /// The response for a connect request.
@@ -92,9 +193,27 @@ pub struct ConnectRequest {
 pub struct ConnectResponse {
     pub header: SkylineHeader,
     /// The mtu the server will use for this client.
+    /// This is the largest probe that arrived, within `MIN_MTU_SIZE` and `MAX_MTU_SIZE`.
     pub mtu: u16,
     /// The current epoch in seconds on the server.
     pub server_time: u64,
     /// The time sent in the previous packet (ConnectRequest).
     pub client_time: u64,
+    /// The server's ephemeral public key.
+    /// Every online packet after this one is encrypted with the derived session keys.
+    pub key_share: KeyShare,
+    /// Identifies the connection, this is sent in every sealed packet so the connection
+    /// survives the client's address changing.
+    pub connection_id: u64,
+}
+
+/// Sent by the server in response to a `ConnectRequest` without a valid cookie.
+/// The client should resend it's `ConnectRequest` with this cookie.
+///
+/// The server does not keep any state until the cookie is echoed back, and this packet
+/// is never larger than the request that triggered it. This prevents spoofed requests from
+/// exhausting the server or using it to amplify traffic.
+#[derive(BinaryIo)]
+pub struct ConnectRetry {
+    pub cookie: Cookie,
 }
@@ -6,9 +6,9 @@ pub trait Acknowledgeable {
     /// We should ack the queue.
     fn ack(&mut self, _: Acknowledgement) {}
 
-    fn nack(&mut self, _: Acknowledgement) -> Vec<Self::NackItem> {
-        todo!("implement nack")
-    }
+    /// When a nack packet is recieved.
+    /// Returns the items that should be resent.
+    fn nack(&mut self, nack: Acknowledgement) -> Vec<Self::NackItem>;
 }
 
 #[derive(BinaryIo)]
@@ -21,11 +21,58 @@ pub enum AckVariant {
     Nack(Acknowledgement) = 0,
 }
 
+/// The most sequences a single `AckRecord::Range` is allowed to cover.
+/// Anything larger can not be inside of the recieve window, so it is truncated.
+pub const MAX_RECORD_SPAN: u32 = 2048;
+
+/// An inclusive range of sequences.
+#[derive(BinaryIo, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeqRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+/// A single entry in an `Acknowledgement`.
+/// Consecutive sequences are collapsed into a range, so a fully recieved window
+/// costs a single record.
+#[derive(BinaryIo, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AckRecord {
+    Single(u32) = 0,
+    Range(SeqRange) = 1,
+}
+
+impl AckRecord {
+    /// The first sequence in this record.
+    pub fn start(&self) -> u32 {
+        match self {
+            AckRecord::Single(seq) => *seq,
+            AckRecord::Range(range) => range.start,
+        }
+    }
+
+    /// The last sequence in this record.
+    pub fn end(&self) -> u32 {
+        match self {
+            AckRecord::Single(seq) => *seq,
+            AckRecord::Range(range) => range.end,
+        }
+    }
+
+    /// The size of this record on the wire.
+    pub fn encoded_size(&self) -> usize {
+        match self {
+            AckRecord::Single(_) => 1 + 4,
+            AckRecord::Range(_) => 1 + 4 + 4,
+        }
+    }
+}
+
 #[derive(BinaryIo, Clone)]
 pub struct Acknowledgement {
-    /// A list of sequences we are missing.
+    /// The sequences this acknowledgement is for, collapsed into ranges.
     /// (In no particular order)
-    pub seqs: Vec<u32>,
+    pub records: Vec<AckRecord>,
     /// If this is true,
     /// it is a list of acknowledgements to sent split packets.
     pub splits: Option<Vec<u32>>,
@@ -34,13 +81,39 @@ pub struct Acknowledgement {
 impl Acknowledgement {
     pub fn new() -> Self {
         Self {
-            seqs: Vec::new(),
+            records: Vec::new(),
             splits: None,
         }
     }
 
+    /// Creates an acknowledgement for the given sequences.
+    /// The sequences are sorted so consecutive sequences share a record.
+    pub fn from_seqs(seqs: impl IntoIterator<Item = u32>) -> Self {
+        let mut seqs = seqs.into_iter().collect::<Vec<u32>>();
+        seqs.sort_unstable();
+        seqs.dedup();
+
+        let mut ack = Self::new();
+        seqs.into_iter().for_each(|seq| ack.add(seq));
+        ack
+    }
+
+    /// Adds a sequence, if it directly follows the last record
+    /// the record is extended instead.
     pub fn add(&mut self, seq: u32) {
-        self.seqs.push(seq);
+        if let Some(last) = self.records.last_mut() {
+            if last.end().wrapping_add(1) == seq
+                && seq.wrapping_sub(last.start()) < MAX_RECORD_SPAN
+            {
+                *last = AckRecord::Range(SeqRange {
+                    start: last.start(),
+                    end: seq,
+                });
+                return;
+            }
+        }
+
+        self.records.push(AckRecord::Single(seq));
     }
 
     pub fn add_split(&mut self, seq: u32) {
@@ -50,6 +123,53 @@ impl Acknowledgement {
         self.splits.as_mut().unwrap().push(seq);
     }
 
+    /// Every sequence in this acknowledgement.
+    /// Ranges larger than `MAX_RECORD_SPAN` are truncated.
+    pub fn seqs(&self) -> Vec<u32> {
+        let mut seqs = Vec::new();
+
+        for record in self.records.iter() {
+            let span = record
+                .end()
+                .wrapping_sub(record.start())
+                .min(MAX_RECORD_SPAN - 1);
+
+            (0..=span).for_each(|offset| seqs.push(record.start().wrapping_add(offset)));
+        }
+
+        seqs
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty() && self.splits.is_none()
+    }
+
+    /// Splits this acknowledgement into acknowledgements whose records
+    /// take up at most `max_size` bytes, so each one fits in a single datagram.
+    pub fn into_chunks(self, max_size: usize) -> Vec<Acknowledgement> {
+        let mut chunks = Vec::new();
+        let mut current = Acknowledgement::new();
+        let mut size = 0;
+
+        for record in self.records.into_iter() {
+            if size + record.encoded_size() > max_size && current.records.len() > 0 {
+                chunks.push(std::mem::replace(&mut current, Acknowledgement::new()));
+                size = 0;
+            }
+
+            size += record.encoded_size();
+            current.records.push(record);
+        }
+
+        current.splits = self.splits;
+
+        if !current.is_empty() {
+            chunks.push(current);
+        }
+
+        chunks
+    }
+
     pub fn to_nack(&self) -> AckVariant {
         AckVariant::Nack(self.clone())
     }
@@ -41,6 +41,11 @@ impl DataBit {
         self
     }
 
+    pub fn with_sequenced(mut self) -> Self {
+        self.bit |= DataBits::Sequenced as u8;
+        self
+    }
+
     pub fn is_reliable(&self) -> bool {
         (self.bit & DataBits::Reliable as u8) != 0
     }
@@ -57,6 +62,10 @@ impl DataBit {
         (self.bit & DataBits::Unreliable as u8) != 0
     }
 
+    pub fn is_sequenced(&self) -> bool {
+        (self.bit & DataBits::Sequenced as u8) != 0
+    }
+
     pub fn get(&self) -> u8 {
         self.bit
     }
@@ -92,6 +101,9 @@ pub enum DataBits {
     /// The packet is unreliable.
     /// Default bit
     Unreliable = 0b0000_1000,
+    /// The packet is Sequenced.
+    /// Only the newest packet on a channel is processed, anything older is dropped.
+    Sequenced = 0b0001_0000,
 }
 
 impl DataBits {
@@ -110,6 +122,10 @@ impl DataBits {
     pub fn is_unreliable(&self) -> bool {
         (*self & DataBits::Unreliable) != 0
     }
+
+    pub fn is_sequenced(&self) -> bool {
+        (*self & DataBits::Sequenced) != 0
+    }
 }
 
 impl BitAnd for DataBits {
@@ -133,8 +149,11 @@ pub struct SplitInfo {
 /// This is primarily used when performing db queries.
 #[derive(Debug, Clone, BinaryIo)]
 pub struct OrderInfo {
+    /// The channel this packet is ordered on.
     pub id: u16,
+    /// The order index, used by `Ordered` packets.
     pub index: u32,
+    /// The sequence on the channel, used by `Sequenced` packets.
     pub sequence: u32,
 }
 
@@ -200,8 +219,8 @@ pub struct DataSet {
     /// If the flags contain the `Split` flag, this will contain the information
     #[satisfy(self.flags.is_split())]
     pub split: Option<SplitInfo>,
-    /// If the flags contain `Ordered`, the order information will be here.
-    #[satisfy(self.flags.is_ordered())]
+    /// If the flags contain `Ordered` or `Sequenced`, the order information will be here.
+    #[satisfy(self.flags.is_ordered() || self.flags.is_sequenced())]
     pub order: Option<OrderInfo>,
     /// the payload, this is prefixed by a varu32 length by binary_util.
     pub payload: SizedVec<u16, u8>,
@@ -246,6 +265,16 @@ impl DataSet {
         self
     }
 
+    pub fn with_sequenced(mut self, id: u16, sequence: u32) -> Self {
+        self.flags = self.flags.with_sequenced();
+        self.order = Some(OrderInfo {
+            id,
+            index: 0,
+            sequence,
+        });
+        self
+    }
+
     pub fn is_split(&self) -> bool {
         self.split.is_some()
     }
@@ -2,6 +2,7 @@ use binary_util::{types::varu32, BinaryIo};
 
 pub mod ack;
 pub mod dataset;
+pub mod sealed;
 
 /// Online packets have differing ids from offline ones!
 #[derive(BinaryIo)]
@@ -17,13 +18,16 @@ pub enum OnlinePackets {
     Datagram(dataset::Datagram),
     /// Used to recover lost packets.
     Ack(ack::AckVariant),
+    /// Any of the above, encrypted with the session keys.
+    /// Once the handshake is complete, every online packet is sealed.
+    Sealed(sealed::Sealed),
 }
 
 /// This is a generic ping packet.
 /// It is used to measure the latency between the client and the server.
 #[derive(BinaryIo)]
 pub struct Ping {
-    /// The time the packet was sent.
+    /// The time the packet was sent, in milliseconds.
     pub send: u64,
 }
 
@@ -32,6 +36,6 @@ pub struct Ping {
 pub struct Pong {
     /// Payload from the ping packet.
     pub send: u64,
-    /// The time the packet was recieved by the peer.
+    /// The time the packet was recieved by the peer, in milliseconds.
     pub recv: u64,
 }
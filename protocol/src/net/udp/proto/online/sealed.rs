@@ -0,0 +1,30 @@
+use binary_util::BinaryIo;
+
+/// Seperates the nonce space of datagrams and control packets,
+/// as both are numbered from zero.
+#[derive(Debug, Clone, Copy, BinaryIo, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SealedKind {
+    /// A `Datagram`, the sequence is the datagram's sequence.
+    Datagram = 0,
+    /// Any other online packet, IE: `Ack`, `Ping` or `Pong`.
+    /// The sequence is a seperate counter for control packets.
+    Control,
+}
+
+/// An online packet that has been encrypted with the session keys.
+///
+/// The ciphertext is an encoded `OnlinePackets`, sealed with ChaCha20-Poly1305.
+/// The nonce is made up of the `phase`, `kind` and `sequence`, which are authenticated
+/// along with the `connection_id`, so none of them can be tampered with.
+#[derive(Debug, Clone, BinaryIo)]
+pub struct Sealed {
+    /// The key phase, this flips every time the sender rotates it's key.
+    pub phase: u8,
+    pub kind: SealedKind,
+    /// The id the server issued for this connection in it's `ConnectResponse`.
+    /// This lets the server find the connection if the client's address changes.
+    pub connection_id: u64,
+    pub sequence: u32,
+    pub ciphertext: Vec<u8>,
+}
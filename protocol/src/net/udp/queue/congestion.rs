@@ -0,0 +1,265 @@
+/// The amount of datagrams (in mtu sized units) the congestion window starts with.
+pub const INITIAL_WINDOW_PACKETS: u64 = 10;
+/// The congestion window never shrinks below this many mtu sized datagrams.
+pub const MIN_WINDOW_PACKETS: u64 = 2;
+/// How much faster than `cwnd / srtt` the pacer is allowed to send.
+/// Pacing slightly faster than the window allows the window to actually be filled.
+const PACING_GAIN: f64 = 1.25;
+
+/// A NewReno style congestion controller.
+///
+/// The congestion window is counted in bytes in flight, a byte is "in flight" when it was sent
+/// reliably and has not been acknowledged yet. The window grows exponentially during slow start
+/// and linearly during congestion avoidance. Loss reported by a `Nack` halves the window once per
+/// round trip, a retransmission timeout collapses the window to the minimum.
+#[derive(Debug, Clone)]
+pub struct CongestionController {
+    mtu: u64,
+    /// The congestion window, in bytes.
+    cwnd: u64,
+    /// The slow start threshold, in bytes.
+    ssthresh: u64,
+    /// The amount of bytes sent reliably that have not been acknowledged.
+    bytes_in_flight: u64,
+    /// Bytes acknowledged during congestion avoidance that haven't grown the window yet.
+    acked_bytes: u64,
+    /// Packets sent before this time (in milliseconds) do not trigger another window reduction.
+    recovery_start: u64,
+    /// The pacer's available budget, in bytes.
+    tokens: f64,
+    /// The last time the pacer was refilled, in milliseconds.
+    last_refill: u64,
+}
+
+impl CongestionController {
+    pub fn new(mtu: u16) -> Self {
+        let mtu = mtu as u64;
+        Self {
+            mtu,
+            cwnd: mtu * INITIAL_WINDOW_PACKETS,
+            ssthresh: u64::MAX,
+            bytes_in_flight: 0,
+            acked_bytes: 0,
+            recovery_start: 0,
+            tokens: (mtu * INITIAL_WINDOW_PACKETS) as f64,
+            last_refill: 0,
+        }
+    }
+
+    fn min_window(&self) -> u64 {
+        self.mtu * MIN_WINDOW_PACKETS
+    }
+
+    /// Whether `size` more bytes can be put in flight without exceeding the window,
+    /// or the pace the window allows.
+    pub fn can_send(&self, size: usize) -> bool {
+        let size = size as u64;
+
+        // a single datagram is always allowed if nothing is in flight, otherwise
+        // a window smaller than a datagram would stall the connection.
+        if self.bytes_in_flight > 0 && self.bytes_in_flight + size > self.cwnd {
+            return false;
+        }
+
+        self.tokens >= size as f64 || self.bytes_in_flight == 0
+    }
+
+    /// Refills the pacer based on the time that has passed since the last refill.
+    /// `srtt` is the smoothed round trip time and `now` the current time, in milliseconds.
+    pub fn refill(&mut self, srtt: Option<u64>, now: u64) {
+        let elapsed = now.saturating_sub(self.last_refill);
+        self.last_refill = now;
+
+        let rate = self.pacing_rate(srtt);
+        // allow small bursts, but never more than the window.
+        let burst = (self.cwnd as f64).min((self.mtu * 4) as f64).max(self.mtu as f64);
+
+        self.tokens = (self.tokens + rate * elapsed as f64 / 1000.0).min(burst);
+    }
+
+    /// The rate the pacer allows, in bytes per second.
+    pub fn pacing_rate(&self, srtt: Option<u64>) -> f64 {
+        // without a round trip sample, pace the initial window over 100ms.
+        let srtt = srtt.unwrap_or(100).max(1);
+        (self.cwnd as f64 * 1000.0 / srtt as f64) * PACING_GAIN
+    }
+
+    /// Called when a datagram with `size` bytes is sent reliably.
+    pub fn on_sent(&mut self, size: usize) {
+        self.bytes_in_flight += size as u64;
+        self.tokens -= size as f64;
+    }
+
+    /// Called when a datagram is sent that does not count towards the window,
+    /// IE: unreliable datagrams. These are still paced.
+    pub fn on_sent_unreliable(&mut self, size: usize) {
+        self.tokens -= size as f64;
+    }
+
+    /// Called when a datagram with `size` bytes is acknowledged.
+    pub fn on_ack(&mut self, size: usize) {
+        let size = size as u64;
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(size);
+
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += size;
+        } else {
+            // congestion avoidance, grow by one mtu every window.
+            self.acked_bytes += size;
+            if self.acked_bytes >= self.cwnd {
+                self.acked_bytes -= self.cwnd;
+                self.cwnd += self.mtu;
+            }
+        }
+    }
+
+    /// Called when the peer reports a datagram as missing.
+    /// `sent` is the time (in milliseconds) the lost datagram was sent.
+    pub fn on_loss(&mut self, sent: u64, now: u64) {
+        if sent < self.recovery_start {
+            // this loss is part of a window we already reduced.
+            return;
+        }
+
+        self.recovery_start = now;
+        self.ssthresh = (self.cwnd / 2).max(self.min_window());
+        self.cwnd = self.ssthresh;
+        self.acked_bytes = 0;
+    }
+
+    /// Called when a datagram was not acknowledged within the retransmission timeout.
+    pub fn on_timeout(&mut self, now: u64) {
+        self.recovery_start = now;
+        self.ssthresh = (self.cwnd / 2).max(self.min_window());
+        self.cwnd = self.min_window();
+        self.acked_bytes = 0;
+    }
+
+    /// Called when a datagram is dropped from the recovery queue without being acknowledged.
+    pub fn on_discard(&mut self, size: usize) {
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(size as u64);
+    }
+
+    /// The congestion window, in bytes.
+    pub fn cwnd(&self) -> u64 {
+        self.cwnd
+    }
+
+    /// The slow start threshold, in bytes.
+    pub fn ssthresh(&self) -> u64 {
+        self.ssthresh
+    }
+
+    /// The amount of bytes sent reliably that have not been acknowledged.
+    pub fn bytes_in_flight(&self) -> u64 {
+        self.bytes_in_flight
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CongestionController, INITIAL_WINDOW_PACKETS, MIN_WINDOW_PACKETS};
+
+    const MTU: u64 = 1000;
+
+    fn controller() -> CongestionController {
+        CongestionController::new(MTU as u16)
+    }
+
+    #[test]
+    fn slow_start() {
+        let mut cc = controller();
+        assert_eq!(cc.cwnd(), MTU * INITIAL_WINDOW_PACKETS);
+        assert_eq!(cc.ssthresh(), u64::MAX);
+
+        for _ in 0..INITIAL_WINDOW_PACKETS {
+            cc.on_sent(MTU as usize);
+        }
+        assert_eq!(cc.bytes_in_flight(), MTU * INITIAL_WINDOW_PACKETS);
+        assert!(!cc.can_send(MTU as usize));
+
+        // every acknowledged byte grows the window by a byte.
+        for _ in 0..INITIAL_WINDOW_PACKETS {
+            cc.on_ack(MTU as usize);
+        }
+        assert_eq!(cc.bytes_in_flight(), 0);
+        assert_eq!(cc.cwnd(), 2 * MTU * INITIAL_WINDOW_PACKETS);
+    }
+
+    #[test]
+    fn loss_halves_once_per_recovery() {
+        let mut cc = controller();
+        let cwnd = cc.cwnd();
+
+        cc.on_loss(10, 20);
+        assert_eq!(cc.cwnd(), cwnd / 2);
+        assert_eq!(cc.ssthresh(), cwnd / 2);
+
+        // sent before the recovery started, part of the same loss event.
+        cc.on_loss(15, 30);
+        assert_eq!(cc.cwnd(), cwnd / 2);
+
+        // sent after the recovery started, a new loss event.
+        cc.on_loss(25, 40);
+        assert_eq!(cc.cwnd(), (cwnd / 4).max(MTU * MIN_WINDOW_PACKETS));
+
+        cc.on_loss(45, 50);
+        assert_eq!(cc.cwnd(), MTU * MIN_WINDOW_PACKETS);
+    }
+
+    #[test]
+    fn recovery_exits_into_congestion_avoidance() {
+        let mut cc = controller();
+        cc.on_loss(0, 10);
+        let cwnd = cc.cwnd();
+
+        // the window only grows by one mtu per window of acknowledged bytes.
+        for _ in 0..(cwnd / MTU) - 1 {
+            cc.on_sent(MTU as usize);
+            cc.on_ack(MTU as usize);
+        }
+        assert_eq!(cc.cwnd(), cwnd);
+
+        cc.on_sent(MTU as usize);
+        cc.on_ack(MTU as usize);
+        assert_eq!(cc.cwnd(), cwnd + MTU);
+    }
+
+    #[test]
+    fn timeout_collapses_window() {
+        let mut cc = controller();
+        let cwnd = cc.cwnd();
+
+        cc.on_timeout(10);
+        assert_eq!(cc.cwnd(), MTU * MIN_WINDOW_PACKETS);
+        assert_eq!(cc.ssthresh(), cwnd / 2);
+    }
+
+    #[test]
+    fn pacing() {
+        let mut cc = controller();
+        assert_eq!(cc.pacing_rate(None), (cc.cwnd() * 10) as f64 * 1.25);
+
+        for _ in 0..INITIAL_WINDOW_PACKETS {
+            cc.on_sent(MTU as usize);
+        }
+        for _ in 0..5 {
+            cc.on_ack(MTU as usize);
+        }
+
+        // there is room in the window, but the pacer is empty.
+        assert!(cc.bytes_in_flight() + MTU <= cc.cwnd());
+        assert!(!cc.can_send(MTU as usize));
+
+        // 15000 bytes over 100ms at 1.25 gain is 1875 bytes in 10ms.
+        cc.refill(Some(100), 10);
+        assert!(cc.can_send(MTU as usize));
+        assert!(!cc.can_send(2 * MTU as usize));
+
+        // a long pause only refills up to the burst size.
+        cc.refill(Some(100), 10_000);
+        assert!(cc.can_send(4 * MTU as usize));
+        assert!(!cc.can_send(5 * MTU as usize));
+    }
+}
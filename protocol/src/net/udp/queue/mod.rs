@@ -1,6 +1,8 @@
+pub mod congestion;
 pub mod ord;
 pub mod recovery;
 pub mod recv;
+pub mod rtt;
 pub mod send;
 pub mod split;
 pub mod window;
@@ -1,5 +1,7 @@
 use std::collections::BTreeMap;
 
+use crate::util::serial;
+
 /// An ordered queue is used to Index incoming packets over a channel.
 #[derive(Debug, Clone)]
 pub struct OrdQueue<Item: Clone + std::fmt::Debug> {
@@ -24,7 +26,7 @@ where
     }
 
     pub fn insert(&mut self, index: u32, item: Item) -> std::io::Result<()> {
-        if index < self.window.0 {
+        if serial::lt(index, self.window.0) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::Other,
                 "Index is out of bounds",
@@ -37,7 +39,7 @@ where
             ));
         }
 
-        if index >= self.window.1 {
+        if serial::ge(index, self.window.1) {
             self.window.1 = index.wrapping_add(1);
         }
 
@@ -46,7 +48,7 @@ where
     }
 
     pub fn insert_abs(&mut self, index: u32, item: Item) -> std::io::Result<()> {
-        if index >= self.window.1 {
+        if serial::ge(index, self.window.1) {
             self.window.1 = index.wrapping_add(1);
         }
 
@@ -55,19 +57,56 @@ where
     }
 
     pub fn missing(&self) -> Vec<u32> {
-        (self.window.0..self.window.1)
+        serial::range(self.window.0, self.window.1)
             .filter(|x| !self.queue.contains_key(x))
             .collect()
     }
 
+    /// Flushes every item that is in order, starting at the start of the window.
+    /// This stops at the first missing index, as items after it are not in order yet.
     pub fn flush(&mut self) -> Vec<Item> {
-        (self.window.0..self.window.1)
-            .filter_map(|x| {
-                if let Some(item) = self.queue.remove(&x) {
-                    return Some(item);
-                }
-                return None;
-            })
-            .collect()
+        let mut items = Vec::new();
+
+        while let Some(item) = self.queue.remove(&self.window.0) {
+            items.push(item);
+            self.next();
+        }
+
+        items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::OrdQueue;
+
+    #[test]
+    fn flush_in_order_across_wrap() {
+        let mut queue = OrdQueue::<u32> {
+            queue: BTreeMap::new(),
+            window: (u32::MAX - 1, u32::MAX - 1),
+        };
+
+        queue.insert(0, 0).unwrap();
+        assert!(queue.flush().is_empty());
+        assert_eq!(queue.missing(), vec![u32::MAX - 1, u32::MAX]);
+
+        queue.insert(u32::MAX, u32::MAX).unwrap();
+        assert!(queue.insert(u32::MAX, u32::MAX).is_err());
+        assert!(queue.flush().is_empty());
+
+        queue.insert(u32::MAX - 1, u32::MAX - 1).unwrap();
+        assert_eq!(queue.flush(), vec![u32::MAX - 1, u32::MAX, 0]);
+        assert_eq!(queue.window, (1, 1));
+
+        // these have already been flushed.
+        assert!(queue.insert(u32::MAX - 1, u32::MAX - 1).is_err());
+        assert!(queue.insert(0, 0).is_err());
+
+        queue.insert(1, 1).unwrap();
+        assert_eq!(queue.flush(), vec![1]);
+        assert!(queue.missing().is_empty());
     }
 }
@@ -1,16 +1,27 @@
 use std::collections::HashMap;
 
-use crate::util::current_epoch;
+use crate::util::current_epoch_ms;
 
 use super::{NetQueue, NetQueueError};
 
+/// A packet waiting in the recovery queue.
+#[derive(Debug, Clone)]
+pub struct RecoveryEntry<Item> {
+    /// When the packet was last sent, in milliseconds.
+    pub sent: u64,
+    /// The amount of times this packet has been resent.
+    pub retries: u8,
+    /// The size of the packet on the wire, in bytes.
+    pub size: usize,
+    pub item: Item,
+}
+
 /// A recovery queue is used to store packets that need to be resent.
 /// This is used for sequenced and ordered packets.
 #[derive(Debug, Clone)]
 pub struct RecoveryQueue<Item> {
-    /// The current queue of packets by timestamp
-    /// (seq, (packet, timestamp))
-    queue: HashMap<u32, (u64, Item)>,
+    /// The current queue of packets by sequence
+    queue: HashMap<u32, RecoveryEntry<Item>>,
 }
 
 impl<Item> RecoveryQueue<Item>
@@ -23,28 +34,65 @@ where
         }
     }
 
-    pub fn insert_id(&mut self, seq: u32, item: Item) {
-        self.queue.insert(seq, (current_epoch(), item));
+    /// Inserts a packet that was sent at `now`, in milliseconds.
+    pub fn insert_id(&mut self, seq: u32, size: usize, item: Item, now: u64) {
+        self.queue.insert(
+            seq,
+            RecoveryEntry {
+                sent: now,
+                retries: 0,
+                size,
+                item,
+            },
+        );
+    }
+
+    /// Gets the entry for a packet that is waiting to be acknowledged.
+    pub fn entry(&self, seq: u32) -> Option<&RecoveryEntry<Item>> {
+        self.queue.get(&seq)
+    }
+
+    /// Removes a packet without acknowledging it.
+    pub fn discard(&mut self, seq: u32) -> Option<RecoveryEntry<Item>> {
+        self.queue.remove(&seq)
     }
 
     pub fn get_all(&mut self) -> Vec<(u32, Item)> {
         self.queue
             .iter()
-            .map(|(seq, (_, item))| (*seq, item.clone()))
+            .map(|(seq, entry)| (*seq, entry.item.clone()))
             .collect::<Vec<_>>()
     }
 
-    pub fn flush_old(&mut self, threshold: u64) -> Vec<Item> {
+    /// Removes every packet that is older than `threshold` milliseconds.
+    pub fn flush_old(&mut self, threshold: u64, now: u64) -> Vec<Item> {
         let old = self
             .queue
             .iter()
-            .filter(|(_, (time, _))| (*time + threshold) < current_epoch())
-            .map(|(_, (_, item))| item.clone())
+            .filter(|(_, entry)| (entry.sent + threshold) < now)
+            .map(|(_, entry)| entry.item.clone())
             .collect::<Vec<_>>();
-        self.queue
-            .retain(|_, (time, _)| (*time + threshold) > current_epoch());
+        self.queue.retain(|_, entry| (entry.sent + threshold) >= now);
         old
     }
+
+    /// Marks a packet as resent outside of a timeout, IE: when the peer
+    /// explicitly told us it is missing.
+    pub fn mark_resent(&mut self, seq: u32, now: u64) {
+        if let Some(entry) = self.queue.get_mut(&seq) {
+            entry.sent = now;
+            entry.retries = entry.retries.saturating_add(1);
+        }
+    }
+
+    /// Removes an acknowledged packet from the queue.
+    pub fn acknowledge(&mut self, seq: u32) -> Option<RecoveryEntry<Item>> {
+        self.queue.remove(&seq)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
 }
 
 impl<Item> NetQueue<Item> for RecoveryQueue<Item> {
@@ -53,21 +101,29 @@ impl<Item> NetQueue<Item> for RecoveryQueue<Item> {
 
     fn insert(&mut self, item: Item) -> Result<Self::KeyId, NetQueueError<Self::Error>> {
         let index = self.queue.len() as u32;
-        self.queue.insert(index, (current_epoch(), item));
+        self.queue.insert(
+            index,
+            RecoveryEntry {
+                sent: current_epoch_ms() as u64,
+                retries: 0,
+                size: 0,
+                item,
+            },
+        );
         Ok(index)
     }
 
     fn remove(&mut self, key: Self::KeyId) -> Result<Item, NetQueueError<Self::Error>> {
-        if let Some((_, item)) = self.queue.remove(&key) {
-            Ok(item)
+        if let Some(entry) = self.queue.remove(&key) {
+            Ok(entry.item)
         } else {
             Err(NetQueueError::ItemDeletionFail)
         }
     }
 
     fn get(&mut self, key: Self::KeyId) -> Result<&Item, NetQueueError<Self::Error>> {
-        if let Some((_, item)) = self.queue.get(&key) {
-            Ok(item)
+        if let Some(entry) = self.queue.get(&key) {
+            Ok(&entry.item)
         } else {
             Err(NetQueueError::ItemDeletionFail)
         }
@@ -75,8 +131,8 @@ impl<Item> NetQueue<Item> for RecoveryQueue<Item> {
 
     fn flush(&mut self) -> Result<Vec<Item>, NetQueueError<Self::Error>> {
         let mut items = Vec::new();
-        for (_, (_, item)) in self.queue.drain() {
-            items.push(item);
+        for (_, entry) in self.queue.drain() {
+            items.push(entry.item);
         }
         Ok(items)
     }
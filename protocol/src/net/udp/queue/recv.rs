@@ -1,14 +1,24 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 use crate::{
     net::udp::proto::online::{
         ack::{Acknowledgeable, Acknowledgement},
         dataset::{DataBits, DataSet, Datagram},
     },
-    MAX_SPLIT_SIZE,
+    util::serial,
+    MAX_ORDER_CHANNELS, MAX_SPLIT_SIZE,
 };
 
-use super::{ord::OrdQueue, split::SplitQueue, window::Window};
+use super::{
+    ord::OrdQueue,
+    split::{SplitLimits, SplitQueue, SplitQueueError},
+    window::Window,
+};
+
+/// The amount of times a missing datagram is reported to the sender before we give up on it.
+/// The sender resends the sets of a lost datagram in new datagrams, so the missing sequence
+/// itself is never recieved. Once reported, the gap is pruned from the window.
+pub const MAX_NACK_REPORTS: u8 = 3;
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum RecvQueueError {
@@ -16,21 +26,42 @@ pub enum RecvQueueError {
     OldSequence,
     /// old reliable sequence
     OldReliableSequence,
+    /// The reliable sequence is past the end of the reliable window.
+    /// The datagram is not acknowledged, so the sender resends the set later.
+    ReliableSequenceTooNew,
     /// Too many splits within packet
     SplitSizeTooLarge,
     /// We are still missing some packets in the split
     MissingIndicesInSplit,
+    /// The peer has exceeded the `SplitLimits` of the queue.
+    /// This is fatal, the connection should be dropped.
+    SplitLimitExceeded,
+    /// The set is ordered on a channel that is not below `MAX_ORDER_CHANNELS`.
+    InvalidOrderChannel,
+}
+
+impl RecvQueueError {
+    /// Whether the connection should be dropped because of this error.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            RecvQueueError::SplitSizeTooLarge | RecvQueueError::SplitLimitExceeded => true,
+            _ => false,
+        }
+    }
 }
 
 pub struct RecvQueue {
     split_q: SplitQueue,
     order_q: HashMap<u16, OrdQueue<Vec<u8>>>,
+    /// The newest sequence processed on each sequenced channel.
+    sequenced: HashMap<u16, u32>,
     /// Acks that we have acknowledged.
     // ack: HashMap<u32, u64>,
     //              seq, epoch - Removed because we don't need to know when we acked it.
-    ack: HashSet<u32>,
+    ack: BTreeSet<u32>,
     /// We don't have these packets..
-    nack: HashSet<u32>,
+    /// (seq, times reported)
+    nack: BTreeMap<u32, u8>,
     /// Packets that we have recieved.
     window: Window,
     reliable_window: Window,
@@ -40,78 +71,126 @@ pub struct RecvQueue {
 
 impl RecvQueue {
     pub fn new() -> Self {
+        Self::with_limits(SplitLimits::default())
+    }
+
+    /// Creates a recieve queue that drops the peer if it exceeds `limits` while sending splits.
+    pub fn with_limits(limits: SplitLimits) -> Self {
         Self {
-            split_q: SplitQueue::new(),
+            split_q: SplitQueue::new().with_limits(limits),
             order_q: HashMap::new(),
-            ack: HashSet::new(),
-            nack: HashSet::new(),
+            sequenced: HashMap::new(),
+            ack: BTreeSet::new(),
+            nack: BTreeMap::new(),
             window: Window::new(),
             reliable_window: Window::new(),
             queue: Vec::new(),
         }
     }
 
-    pub fn insert(&mut self, datagram: Datagram) -> Result<(), RecvQueueError> {
+    /// Inserts a datagram recieved at `now`, in milliseconds.
+    pub fn insert(&mut self, datagram: Datagram, now: u64) -> Result<(), RecvQueueError> {
         if !self.window.insert(datagram.sequence.into()) {
             return Err(RecvQueueError::OldSequence);
         }
 
-        if self.window.window().start < datagram.sequence.into() {
+        // if this sequence was missing, it no longer is.
+        self.nack.remove(&datagram.sequence);
+
+        if serial::lt(self.window.window().start, datagram.sequence) {
             // this is a new packet, we might not have previous packets!
-            (self.window.window().start..(datagram.sequence))
+            self.window
+                .missing()
                 .into_iter()
+                .filter(|seq| serial::lt(*seq, datagram.sequence))
                 .for_each(|seq| {
-                    self.nack.insert(seq);
+                    self.nack.entry(seq).or_insert(0);
                 });
         }
 
-        self.ack.insert(datagram.sequence);
-
         // self.process_data_set(&data_set)?;
         // loop through the data set and process it.
+        let mut too_new = false;
         for set in datagram.sets {
-            if let Err(e) = self.process_data_set(&set) {
+            if let Err(e) = self.process_data_set(&set, now) {
+                if e.is_fatal() {
+                    return Err(e);
+                }
+
+                if e == RecvQueueError::ReliableSequenceTooNew {
+                    too_new = true;
+                }
+
                 println!("[RECQ] Error processing set: {:?}", e);
             }
         }
 
+        // acknowledging the datagram would tell the sender that the rejected set was delivered.
+        if !too_new {
+            self.ack.insert(datagram.sequence);
+        }
+
         Ok(())
     }
 
-    fn process_data_set(&mut self, data_set: &DataSet) -> Result<(), RecvQueueError> {
+    fn process_data_set(&mut self, data_set: &DataSet, now: u64) -> Result<(), RecvQueueError> {
+        // each channel has it's own queue, so the peer can't make us allocate more than we expect.
+        if let Some(ref order_info) = data_set.order {
+            if order_info.id >= MAX_ORDER_CHANNELS as u16 {
+                return Err(RecvQueueError::InvalidOrderChannel);
+            }
+        }
+
         if let Some(ref seq) = data_set.reliable_seq {
             if !self.reliable_window.insert(seq.0) {
+                if serial::ge(seq.0, self.reliable_window.window().end) {
+                    return Err(RecvQueueError::ReliableSequenceTooNew);
+                }
                 return Err(RecvQueueError::OldReliableSequence);
             }
         }
-        if let Some(ref split) = data_set.split {
+        let payload = if let Some(ref split) = data_set.split {
             if split.total > MAX_SPLIT_SIZE.into() {
                 return Err(RecvQueueError::SplitSizeTooLarge);
             }
 
-            if let Err(_) = self.split_q.insert(data_set.clone()) {}
+            if let Err(SplitQueueError::LimitExceeded) = self.split_q.insert(data_set.clone(), now)
+            {
+                return Err(RecvQueueError::SplitLimitExceeded);
+            }
 
             match self.split_q.join(split.id) {
-                Ok(pk) => {
-                    // we have the full packet!
-                    self.queue.push(pk);
-                    return Ok(());
-                }
+                // we have the full packet!
+                Ok(pk) => pk,
                 Err(_) => {
                     // we're still missing some packets
                     return Err(RecvQueueError::MissingIndicesInSplit);
                 }
             }
-        }
+        } else {
+            data_set.payload.clone().data
+        };
 
-        if data_set.flags.is_ordered() {
+        if data_set.flags.is_sequenced() {
+            if let Some(ref order_info) = data_set.order {
+                // only the newest packet on this channel is processed.
+                if let Some(newest) = self.sequenced.get(&order_info.id) {
+                    if !serial::gt(order_info.sequence, *newest) {
+                        return Ok(());
+                    }
+                }
+
+                self.sequenced.insert(order_info.id, order_info.sequence);
+                self.queue.push(payload);
+            }
+        } else if data_set.flags.is_ordered() {
             if let Some(ref order_info) = data_set.order {
                 let channel = self
                     .order_q
                     .entry(order_info.id)
                     .or_insert_with(|| OrdQueue::new());
 
-                if let Ok(_) = channel.insert(order_info.index, data_set.payload.clone().data) {
+                if let Ok(_) = channel.insert(order_info.index, payload) {
                     // we have the packets in order now,
                     // we can push them to the queue.
                     channel.flush().into_iter().for_each(|pk| {
@@ -120,12 +199,18 @@ impl RecvQueue {
                 }
             }
         } else {
-            self.queue.push(data_set.payload.clone().into());
+            self.queue.push(payload);
         }
 
         Ok(())
     }
 
+    /// Discards splits that have not been completed within the timeout.
+    /// Returns the amount of splits discarded.
+    pub fn expire_splits(&mut self, now: u64) -> usize {
+        self.split_q.expire(now).len()
+    }
+
     pub fn flush(&mut self) -> Vec<Vec<u8>> {
         // todo: Determine whether this is better than the contrary
         // todo: EG using an interator:
@@ -135,12 +220,35 @@ impl RecvQueue {
         queue
     }
 
+    /// Returns every sequence recieved since the last flush.
     pub fn ack_flush(&mut self) -> Vec<u32> {
-        self.ack.drain().collect()
+        std::mem::take(&mut self.ack).into_iter().collect()
     }
 
+    /// Returns every sequence we are missing.
+    ///
+    /// Each gap is reported `MAX_NACK_REPORTS` times, after which the sender has either
+    /// recovered it or will recover it on a timeout, so the gap is pruned and the window
+    /// is allowed to move past it.
     pub fn nack_queue(&mut self) -> Vec<u32> {
-        self.nack.iter().map(|x| *x).collect::<Vec<u32>>()
+        let mut missing = Vec::new();
+        let mut recovered = Vec::new();
+
+        for (seq, reports) in self.nack.iter_mut() {
+            *reports += 1;
+            missing.push(*seq);
+
+            if *reports >= MAX_NACK_REPORTS {
+                recovered.push(*seq);
+            }
+        }
+
+        for seq in recovered {
+            self.nack.remove(&seq);
+            self.window.insert(seq);
+        }
+
+        missing
     }
 }
 
@@ -148,8 +256,11 @@ impl Acknowledgeable for RecvQueue {
     type NackItem = ();
 
     fn ack(&mut self, ack: Acknowledgement) {
-        ack.seqs.into_iter().for_each(|seq| {
-            self.nack.remove(&seq);
+        // the sender has recovered these, there's no need to report them again.
+        ack.seqs().into_iter().for_each(|seq| {
+            if self.nack.remove(&seq).is_some() {
+                self.window.insert(seq);
+            }
         });
     }
 
@@ -159,3 +270,128 @@ impl Acknowledgeable for RecvQueue {
         return vec![];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{RecvQueue, RecvQueueError, MAX_NACK_REPORTS};
+    use crate::net::udp::{
+        proto::online::dataset::{DataSet, Datagram},
+        queue::{ord::OrdQueue, window::Window},
+    };
+
+    /// A queue whose datagram and reliable windows start at `start`.
+    fn queue_at(start: u32) -> RecvQueue {
+        let mut queue = RecvQueue::new();
+        queue.window = Window::starting_at(start);
+        queue.reliable_window = Window::starting_at(start);
+        queue
+    }
+
+    fn datagram(seq: u32, set: DataSet) -> Datagram {
+        Datagram::new().with_sequence(seq).with_set(set)
+    }
+
+    #[test]
+    fn nack_across_wrap() {
+        let mut queue = queue_at(u32::MAX - 1);
+
+        queue
+            .insert(
+                datagram(u32::MAX - 1, DataSet::new().with_payload(vec![1])),
+                0,
+            )
+            .unwrap();
+        queue
+            .insert(datagram(1, DataSet::new().with_payload(vec![2])), 0)
+            .unwrap();
+
+        assert_eq!(queue.flush(), vec![vec![1], vec![2]]);
+        assert_eq!(queue.nack_queue(), vec![0, u32::MAX]);
+
+        // 0 arrived late, so only u32::MAX is still missing.
+        queue
+            .insert(datagram(0, DataSet::new().with_payload(vec![3])), 0)
+            .unwrap();
+        assert_eq!(queue.ack_flush(), vec![0, 1, u32::MAX - 1]);
+
+        for _ in 1..MAX_NACK_REPORTS {
+            assert_eq!(queue.nack_queue(), vec![u32::MAX]);
+        }
+
+        // the gap has been reported enough times, the window moves past it.
+        assert!(queue.nack_queue().is_empty());
+        assert_eq!(queue.window.window().start, 2);
+        assert_eq!(
+            queue.insert(datagram(u32::MAX - 1, DataSet::new()), 0),
+            Err(RecvQueueError::OldSequence)
+        );
+    }
+
+    #[test]
+    fn ordered_across_wrap() {
+        let mut queue = queue_at(u32::MAX - 1);
+        queue.order_q.insert(
+            0,
+            OrdQueue {
+                queue: BTreeMap::new(),
+                window: (u32::MAX, u32::MAX),
+            },
+        );
+
+        let set = |reliable: u32, index: u32| {
+            DataSet::new()
+                .with_payload(index.to_be_bytes().to_vec())
+                .with_reliable(reliable)
+                .with_ordered(0, index)
+        };
+
+        queue
+            .insert(datagram(u32::MAX - 1, set(u32::MAX - 1, 0)), 0)
+            .unwrap();
+        assert!(queue.flush().is_empty());
+
+        queue
+            .insert(datagram(u32::MAX, set(u32::MAX, u32::MAX)), 0)
+            .unwrap();
+        assert_eq!(
+            queue.flush(),
+            vec![u32::MAX.to_be_bytes().to_vec(), 0u32.to_be_bytes().to_vec()]
+        );
+
+        // a resent set is dropped by the reliable window, even in a new datagram.
+        queue
+            .insert(datagram(0, set(u32::MAX, u32::MAX)), 0)
+            .unwrap();
+        assert!(queue.flush().is_empty());
+
+        queue.insert(datagram(1, set(0, 1)), 0).unwrap();
+        assert_eq!(queue.flush(), vec![1u32.to_be_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn reliable_past_window_is_not_acked() {
+        let mut queue = queue_at(0);
+        let set = |reliable: u32| {
+            DataSet::new()
+                .with_payload(reliable.to_be_bytes().to_vec())
+                .with_reliable(reliable)
+        };
+
+        queue.insert(datagram(0, set(2048)), 0).unwrap();
+        assert!(queue.flush().is_empty());
+        assert!(queue.ack_flush().is_empty());
+
+        // a duplicate is still acknowledged, the sender has nothing to resend.
+        queue.insert(datagram(1, set(0)), 0).unwrap();
+        queue.insert(datagram(2, set(0)), 0).unwrap();
+        assert_eq!(queue.flush(), vec![0u32.to_be_bytes().to_vec()]);
+        assert_eq!(queue.ack_flush(), vec![1, 2]);
+
+        // once the window has moved, the resent set is accepted.
+        queue.insert(datagram(3, set(2048)), 0).unwrap();
+        assert_eq!(queue.flush(), vec![2048u32.to_be_bytes().to_vec()]);
+        assert_eq!(queue.ack_flush(), vec![3]);
+    }
+}
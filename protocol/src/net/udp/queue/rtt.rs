@@ -0,0 +1,135 @@
+/// The retransmission timeout used before any round trip has been measured.
+pub const INITIAL_RTO: u64 = 1000;
+/// The lowest the retransmission timeout is allowed to go, in milliseconds.
+pub const MIN_RTO: u64 = 100;
+/// The highest the retransmission timeout is allowed to go, in milliseconds.
+/// This also caps the exponential backoff of a lost datagram.
+pub const MAX_RTO: u64 = 10_000;
+/// The amount of times a datagram will be resent before the connection is
+/// considered lost.
+pub const DEFAULT_MAX_RETRIES: u8 = 10;
+
+/// A smoothed round trip time estimator.
+///
+/// This follows the algorithm described in RFC 6298, all values are in milliseconds.
+/// Samples are taken from online `Ping`/`Pong` packets as well as from acknowledgements
+/// of datagrams that were only sent once.
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    /// The smoothed round trip time.
+    srtt: Option<u64>,
+    /// The round trip time variation.
+    rttvar: u64,
+    /// The most recent sample.
+    latest: u64,
+    /// The current retransmission timeout.
+    rto: u64,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: 0,
+            latest: 0,
+            rto: INITIAL_RTO,
+        }
+    }
+
+    /// Feeds a round trip time sample into the estimator.
+    pub fn update(&mut self, sample: u64) {
+        self.latest = sample;
+
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                // rttvar = 3/4 * rttvar + 1/4 * |srtt - sample|
+                // srtt = 7/8 * srtt + 1/8 * sample
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(sample)) / 4;
+                self.srtt = Some((7 * srtt + sample) / 8);
+            }
+        }
+
+        let srtt = self.srtt.unwrap();
+        self.rto = (srtt + (4 * self.rttvar).max(1)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// The retransmission timeout for a datagram that has been resent `retries` times.
+    /// Every retry doubles the timeout, up to `MAX_RTO`.
+    pub fn backoff(&self, retries: u8) -> u64 {
+        self.rto
+            .saturating_mul(1u64.checked_shl(retries as u32).unwrap_or(u64::MAX))
+            .min(MAX_RTO)
+    }
+
+    /// The current retransmission timeout.
+    pub fn rto(&self) -> u64 {
+        self.rto
+    }
+
+    /// The smoothed round trip time, if a sample has been taken.
+    pub fn srtt(&self) -> Option<u64> {
+        self.srtt
+    }
+
+    /// The round trip time variation.
+    pub fn rttvar(&self) -> u64 {
+        self.rttvar
+    }
+
+    /// The most recent round trip time sample.
+    pub fn latest(&self) -> u64 {
+        self.latest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RttEstimator, INITIAL_RTO, MAX_RTO, MIN_RTO};
+
+    #[test]
+    fn smooths_samples() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(), INITIAL_RTO);
+
+        // the first sample sets srtt, and half of it as the variation.
+        rtt.update(200);
+        assert_eq!(rtt.srtt(), Some(200));
+        assert_eq!(rtt.rttvar(), 100);
+        assert_eq!(rtt.rto(), 200 + 4 * 100);
+
+        rtt.update(100);
+        assert_eq!(rtt.latest(), 100);
+        assert_eq!(rtt.rttvar(), (3 * 100 + 100) / 4);
+        assert_eq!(rtt.srtt(), Some((7 * 200 + 100) / 8));
+        assert_eq!(rtt.rto(), 187 + 4 * 100);
+    }
+
+    #[test]
+    fn clamps_rto() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(1);
+        assert_eq!(rtt.rto(), MIN_RTO);
+
+        let mut rtt = RttEstimator::new();
+        rtt.update(20_000);
+        assert_eq!(rtt.rto(), MAX_RTO);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut rtt = RttEstimator::new();
+        rtt.update(200);
+
+        assert_eq!(rtt.backoff(0), 600);
+        assert_eq!(rtt.backoff(1), 1200);
+        assert_eq!(rtt.backoff(2), 2400);
+        assert_eq!(rtt.backoff(4), 9600);
+        assert_eq!(rtt.backoff(5), MAX_RTO);
+        assert_eq!(rtt.backoff(u8::MAX), MAX_RTO);
+    }
+}
@@ -1,22 +1,27 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::collections::{HashMap, VecDeque};
 
 use binary_util::interfaces::Writer;
 
 use super::{
+    congestion::CongestionController,
     recovery::RecoveryQueue,
+    rtt::{RttEstimator, DEFAULT_MAX_RETRIES},
     split::{SplitQueue, SplitQueueError},
 };
 use crate::{
+    net::udp::crypto::SealingKey,
     net::udp::proto::{
         online::{
-            ack::Acknowledgeable,
+            ack::Acknowledgement,
             dataset::{DataBit, DataSet, Datagram, OrderInfo, SplitInfo},
+            sealed::SealedKind,
             OnlinePackets,
         },
-        Packets, MAX_PROTO_OVERHEAD,
+        Packets, IP_UDP_HEADER_SIZE, MAX_MTU_SIZE, MAX_PROTO_OVERHEAD, MIN_MTU_SIZE,
+        SEAL_OVERHEAD,
     },
-    net::udp::queue::{ord, NetQueue},
-    util::SafeGenerator,
+    util::{serial, SafeGenerator},
+    MAX_ORDER_CHANNELS,
 };
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -32,9 +37,36 @@ pub enum SendPriority {
     /// This packet is critical, it should be pushed to the front of the queue
     High,
     /// This packet is critical, it should be sent immediately.
+    /// It skips the queue, but is still resent if it is lost.
     Immediate,
 }
 
+/// How packets on a channel are processed by the peer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum SendOrdering {
+    /// The packet is processed as soon as it arrives.
+    None,
+    /// Packets are processed in the order they were sent,
+    /// the peer waits for any missing packets on the channel.
+    Ordered,
+    /// Only the newest packet on the channel is processed, anything older is dropped.
+    /// This is useful for state updates, where an old update is worse than none.
+    Sequenced,
+}
+
+/// Maps a Skyline channel onto one of the `MAX_ORDER_CHANNELS` ordering channels of a connection.
+///
+/// Ordering channel `0` is reserved for packets that don't belong to a Skyline channel (login, disconnect, etc).
+/// Skyline channel `n` is ordered on `1 + n % (MAX_ORDER_CHANNELS - 1)`, so packets stay ordered within a channel,
+/// but a lost packet on one channel does not hold up another, unless both map onto the same ordering channel.
+pub fn order_channel(channel_id: Option<u16>) -> u16 {
+    match channel_id {
+        Some(id) => 1 + id % (MAX_ORDER_CHANNELS as u16 - 1),
+        None => 0,
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum SendQueueError {
@@ -44,8 +76,60 @@ pub enum SendQueueError {
     SplitError(SplitQueueError),
     /// Could not send the packet.
     SendError,
+    /// A packet was resent too many times without being acknowledged.
+    /// The connection should be considered lost.
+    RetryLimitExceeded,
+    /// The ordering channel is not below `MAX_ORDER_CHANNELS`, see `order_channel`.
+    InvalidOrderChannel,
 }
 
+/// The outcome of a packet sent with `SendQueue::insert_tracked`, by receipt id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Receipt {
+    /// Every part of the packet was acknowledged by the peer.
+    Delivered(u32),
+    /// The packet was dropped before the peer acknowledged it.
+    Failed(u32),
+}
+
+/// A snapshot of the congestion and round trip state of a `SendQueue`.
+/// All times are in milliseconds, all sizes are in bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct SendQueueStats {
+    pub cwnd: u64,
+    pub ssthresh: u64,
+    pub bytes_in_flight: u64,
+    /// The rate the pacer is currently allowing, in bytes per second.
+    pub pacing_rate: f64,
+    pub srtt: Option<u64>,
+    pub rttvar: u64,
+    pub rto: u64,
+    /// The amount of sets waiting to be sent.
+    pub queued: usize,
+}
+
+/// The size of a datagram without any sets.
+/// 1 (packet id) + 2 (online packet id) + 4 (sequence) + 5 (set count)
+const DATAGRAM_HEADER_SIZE: usize = 1 + 2 + 4 + 5;
+
+/// A datagram that has been sent, but not acknowledged (or reported missing) yet.
+#[derive(Debug, Clone)]
+struct InFlight {
+    /// When the datagram was sent, in milliseconds.
+    sent: u64,
+    /// The size of the datagram on the wire.
+    size: usize,
+    /// The reliable sequences of the sets in this datagram.
+    sets: Vec<u32>,
+    /// The highest amount of times any set in this datagram has been resent.
+    retries: u8,
+}
+
+/// Queues packets for a single peer, and decides when they are sent.
+///
+/// The queue does not do any IO, encoded datagrams are buffered until they are taken
+/// with `poll_transmit`. Every method that depends on time takes the current time, in
+/// milliseconds, so the queue behaves the same under any clock.
 pub struct SendQueue {
     mtu_size: u16,
     /// The current sequence number.
@@ -53,41 +137,118 @@ pub struct SendQueue {
     seq: SafeGenerator<u32>,
     /// The reliable sequence number
     reliable_seq: SafeGenerator<u32>,
-    ack: RecoveryQueue<Datagram>,
+    /// Reliable sets that have not been acknowledged, by reliable sequence.
+    ack: RecoveryQueue<DataSet>,
+    /// Datagrams that carry reliable sets and have not been acknowledged, by sequence.
+    in_flight: HashMap<u32, InFlight>,
+    /// Reliable sets that were lost, these are sent before anything else.
+    resend: VecDeque<DataSet>,
+    /// The round trip time estimator, this decides when packets are resent.
+    rtt: RttEstimator,
+    /// The amount of times a packet can be resent before the connection is lost.
+    max_retries: u8,
+    /// Whether a set was dropped because it exceeded `max_retries`, reported on the next update.
+    retries_exceeded: bool,
+    /// Encrypts outgoing online packets, once the handshake is complete.
+    sealing_key: Option<SealingKey>,
+    /// The sequence used to seal online packets that aren't datagrams.
+    control_seq: SafeGenerator<u32>,
+    /// Limits how much data can be in flight, and how fast it is sent.
+    congestion: CongestionController,
     splitq: SplitQueue,
     /// channels
     /// (channel, seq)
     ord_chans: HashMap<u16, (u32, u32)>,
     /// The packets we're ready to process
-    queue: HashMap<SendPriority, Vec<DataSet>>,
-    socket: Arc<tokio::net::UdpSocket>,
-    address: SocketAddr,
+    queue: HashMap<SendPriority, VecDeque<DataSet>>,
+    /// Encoded packets waiting to be sent to the peer.
+    outgoing: VecDeque<Vec<u8>>,
+    receipt_id: SafeGenerator<u32>,
+    /// The receipt of every set sent with `insert_tracked`, by reliable sequence.
+    tracked: HashMap<u32, u32>,
+    /// The amount of sets each receipt is still waiting on.
+    pending_receipts: HashMap<u32, usize>,
+    /// Receipts that are settled, waiting to be taken with `poll_receipt`.
+    receipts: VecDeque<Receipt>,
 }
 
 impl SendQueue {
-    pub fn new(
-        mtu: u16,
-        socket: Arc<tokio::net::UdpSocket>,
-        address: std::net::SocketAddr,
-    ) -> Self {
+    /// Creates a queue for the negotiated `mtu`, which is kept within `MIN_MTU_SIZE` and `MAX_MTU_SIZE`.
+    pub fn new(mtu: u16) -> Self {
+        let mtu = mtu.clamp(MIN_MTU_SIZE, MAX_MTU_SIZE);
+
         Self {
             mtu_size: mtu,
             seq: SafeGenerator::new(),
             ack: RecoveryQueue::new(),
+            in_flight: HashMap::new(),
+            resend: VecDeque::new(),
+            rtt: RttEstimator::new(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retries_exceeded: false,
+            sealing_key: None,
+            control_seq: SafeGenerator::new(),
+            congestion: CongestionController::new(mtu),
             splitq: SplitQueue::new(),
             ord_chans: HashMap::new(),
             queue: HashMap::new(),
-            socket,
-            address,
+            outgoing: VecDeque::new(),
             reliable_seq: SafeGenerator::new(),
+            receipt_id: SafeGenerator::new(),
+            tracked: HashMap::new(),
+            pending_receipts: HashMap::new(),
+            receipts: VecDeque::new(),
         }
     }
 
-    pub async fn insert_writable(
+    /// Sets the amount of times a packet can be resent before the connection is lost.
+    pub fn with_max_retries(mut self, max_retries: u8) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Encrypts every online packet sent by this queue with `key`.
+    pub fn with_sealing_key(mut self, key: SealingKey) -> Self {
+        self.sealing_key = Some(key);
+        self
+    }
+
+    /// Feeds a round trip time sample (in milliseconds) into the estimator.
+    /// This is used by online `Pong` packets.
+    pub fn update_rtt(&mut self, sample: u64) {
+        self.rtt.update(sample);
+    }
+
+    /// The round trip time estimator for this queue.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// The congestion controller for this queue.
+    pub fn congestion(&self) -> &CongestionController {
+        &self.congestion
+    }
+
+    /// Returns a snapshot of the congestion window and round trip time, for diagnostics.
+    pub fn stats(&self) -> SendQueueStats {
+        SendQueueStats {
+            cwnd: self.congestion.cwnd(),
+            ssthresh: self.congestion.ssthresh(),
+            bytes_in_flight: self.congestion.bytes_in_flight(),
+            pacing_rate: self.congestion.pacing_rate(self.rtt.srtt()),
+            srtt: self.rtt.srtt(),
+            rttvar: self.rtt.rttvar(),
+            rto: self.rtt.rto(),
+            queued: self.resend.len() + self.queue.values().map(|q| q.len()).sum::<usize>(),
+        }
+    }
+
+    pub fn insert_writable(
         &mut self,
         any_writer: impl Writer,
         priority: SendPriority,
         channel: Option<u16>,
+        now: u64,
     ) -> Result<(), SendQueueError> {
         let bytes = match any_writer.write_to_bytes() {
             Ok(b) => b,
@@ -96,18 +257,36 @@ impl SendQueue {
             }
         };
 
-        self.insert(bytes.as_slice(), priority, channel).await
+        self.insert(bytes.as_slice(), priority, channel, now)
+    }
+
+    pub fn insert(
+        &mut self,
+        packet: &[u8],
+        priority: SendPriority,
+        channel: Option<u16>,
+        now: u64,
+    ) -> Result<(), SendQueueError> {
+        self.insert_with(packet, priority, SendOrdering::None, channel, now)
     }
 
-    pub async fn insert(
+    /// Inserts a packet that is delivered with the given `ordering` on `channel`.
+    /// Split packets are always ordered, as the peer needs every part.
+    pub fn insert_with(
         &mut self,
         packet: &[u8],
         mut priority: SendPriority,
+        ordering: SendOrdering,
         channel: Option<u16>,
+        now: u64,
     ) -> Result<(), SendQueueError> {
+        if channel.unwrap_or(0) >= MAX_ORDER_CHANNELS as u16 {
+            return Err(SendQueueError::InvalidOrderChannel);
+        }
+
         // we will modify bits depending on the payload size...
         // if a packet is split, it will be marked as reliable.
-        if packet.len() > (self.mtu_size + MAX_PROTO_OVERHEAD) as usize {
+        if packet.len() > (self.mtu_size - MAX_PROTO_OVERHEAD) as usize {
             priority = SendPriority::Medium;
         }
 
@@ -115,57 +294,53 @@ impl SendQueue {
         match priority {
             SendPriority::Low => {
                 // we don't care about this packet, we can drop it if needed.
-                self.send_set(
-                    DataSet::new()
-                        .with_payload(packet.to_vec())
-                        .with_bits(DataBit::new().with_unreliable()),
-                )
-                .await?;
+                let set = DataSet::new()
+                    .with_payload(packet.to_vec())
+                    .with_bits(DataBit::new().with_unreliable());
+                let set = self.order_set(set, ordering, channel);
+                self.send_set(set, now)?;
+                return Ok(());
             }
             _ => {}
         };
 
-        if packet.len() > (self.mtu_size + MAX_PROTO_OVERHEAD) as usize {
+        if packet.len() > (self.mtu_size - MAX_PROTO_OVERHEAD) as usize {
             // we need to split this packet
-            let mut datagram = Datagram::new().with_sequence(self.seq.next().into());
-
             let split_insert = self.splitq.split_insert(&packet, self.mtu_size);
             if let Ok(split_id) = split_insert {
-                let (_, parts) = self.splitq.get_mut(&split_id).unwrap();
-                let (ord_seq, ord_idx) =
-                    self.ord_chans.entry(channel.unwrap_or(0)).or_insert((0, 0));
+                let (_, parts) = self.splitq.remove(&split_id).unwrap();
+                let (_, ord_idx) = self.ord_chans.entry(channel.unwrap_or(0)).or_insert((0, 0));
+
+                let queue = self.queue.entry(SendPriority::Medium).or_insert(VecDeque::new());
 
-                for part in parts.iter_mut() {
+                for mut part in parts.into_iter() {
                     part.flags = DataBit::new().with_split().with_reliable().with_ordered();
 
-                    part.seq = self.seq.next().into();
                     part.reliable_seq = Some(self.reliable_seq.next().into());
                     part.order = Some(OrderInfo {
                         id: channel.unwrap_or(0),
                         index: *ord_idx,
-                        sequence: *ord_seq,
+                        sequence: 0,
                     });
 
-                    datagram.push_set(part.clone());
+                    queue.push_back(part);
                 }
 
-                *ord_idx += 1;
-                *ord_seq += 1;
-
-                // now we need to add all packets in this sequence to the ack queue.
-                // we're doing this in case the packet is lost, we can resend it.
-                if let Err(_) = self.ack.insert(datagram) {
-                    println!("failed to insert ack packet");
-                }
+                *ord_idx = ord_idx.wrapping_add(1);
 
                 // we have queued the packets, will be sent next tick.
+                // each part is added to the recovery queue when it is sent.
                 return Ok(());
             }
 
-            return Err(SendQueueError::SplitError(split_insert.unwrap_err()));
+            return match split_insert.unwrap_err() {
+                SplitQueueError::TooLarge => Err(SendQueueError::PacketTooLarge),
+                e => Err(SendQueueError::SplitError(e)),
+            };
         } else {
             // this packet isn't split, we can send it immediately (if defined by the priority)
-            let mut set = DataSet::new().with_payload(packet.to_vec());
+            let set = DataSet::new().with_payload(packet.to_vec());
+            let mut set = self.order_set(set, ordering, channel);
 
             match priority {
                 SendPriority::High => {
@@ -173,8 +348,8 @@ impl SendQueue {
                     set.reliable_seq = Some(self.reliable_seq.next().into());
                     self.queue
                         .entry(SendPriority::High)
-                        .or_insert(Vec::new())
-                        .push(set);
+                        .or_insert(VecDeque::new())
+                        .push_back(set);
                     Ok(())
                 }
                 SendPriority::Medium => {
@@ -182,12 +357,14 @@ impl SendQueue {
                     set.reliable_seq = Some(self.reliable_seq.next().into());
                     self.queue
                         .entry(SendPriority::Medium)
-                        .or_insert(Vec::new())
-                        .push(set);
+                        .or_insert(VecDeque::new())
+                        .push_back(set);
                     Ok(())
                 }
                 SendPriority::Immediate => {
-                    self.send_set(set).await?;
+                    set.flags = set.flags.with_reliable();
+                    set.reliable_seq = Some(self.reliable_seq.next().into());
+                    self.send_set(set, now)?;
                     Ok(())
                 }
                 _ => Err(SendQueueError::PacketTooLarge),
@@ -195,104 +372,390 @@ impl SendQueue {
         }
     }
 
-    /// A wrapper to send a single set over the network.
-    /// This will automatically set the sequence number and reliable sequence number.
-    async fn send_set(&mut self, mut set: DataSet) -> Result<(), SendQueueError> {
-        // we need to verify that the packet is proper for the payload.
+    /// Inserts a packet like `insert_with`, but returns a receipt id for it.
+    /// Once every part of the packet is acknowledged `poll_receipt` returns `Receipt::Delivered`,
+    /// if the packet is dropped first it returns `Receipt::Failed`.
+    ///
+    /// The packet is always reliable, `Low` priority packets are sent with `Medium` priority.
+    pub fn insert_tracked(
+        &mut self,
+        packet: &[u8],
+        priority: SendPriority,
+        ordering: SendOrdering,
+        channel: Option<u16>,
+        now: u64,
+    ) -> Result<u32, SendQueueError> {
+        let priority = match priority {
+            SendPriority::Low => SendPriority::Medium,
+            priority => priority,
+        };
 
-        if set.flags.is_reliable() {
-            set.reliable_seq = Some(self.reliable_seq.next().into());
+        // every reliable set of this packet is assigned a reliable sequence by the insert.
+        let start = self.reliable_seq.get();
+        self.insert_with(packet, priority, ordering, channel, now)?;
+
+        let id = self.receipt_id.next();
+        let mut pending = 0;
+
+        for seq in serial::range(start, self.reliable_seq.get()) {
+            self.tracked.insert(seq, id);
+            pending += 1;
+        }
+
+        self.pending_receipts.insert(id, pending);
+        Ok(id)
+    }
+
+    /// Settles the receipt of a reliable set, if it has one.
+    /// A receipt is delivered once all of it's sets are, but fails as soon as one is dropped.
+    fn settle(&mut self, seq: u32, delivered: bool) {
+        let id = match self.tracked.remove(&seq) {
+            Some(id) => id,
+            None => return,
+        };
+
+        let pending = match self.pending_receipts.get_mut(&id) {
+            Some(pending) => pending,
+            // the receipt already failed.
+            None => return,
+        };
+
+        *pending = pending.saturating_sub(1);
+
+        if !delivered {
+            self.pending_receipts.remove(&id);
+            self.receipts.push_back(Receipt::Failed(id));
+        } else if *pending == 0 {
+            self.pending_receipts.remove(&id);
+            self.receipts.push_back(Receipt::Delivered(id));
         }
+    }
 
-        let datagram = Datagram::new()
-            .with_sequence(self.seq.next().into())
-            .with_set(set);
+    /// Fails every receipt that is still pending, this is used when the connection is closed.
+    pub fn fail_receipts(&mut self) {
+        self.tracked.clear();
 
-        let packet = Packets::OnlinePacket(OnlinePackets::Datagram(datagram));
+        let mut pending = self.pending_receipts.drain().map(|(id, _)| id).collect::<Vec<u32>>();
+        pending.sort();
 
-        if let Ok(buf) = packet.write_to_bytes() {
-            return self.send_raw(buf.as_slice()).await;
+        for id in pending {
+            self.receipts.push_back(Receipt::Failed(id));
         }
+    }
 
-        Ok(())
+    /// Takes the next settled receipt.
+    pub fn poll_receipt(&mut self) -> Option<Receipt> {
+        self.receipts.pop_front()
     }
 
-    async fn send_datagram(&mut self, datagram: Datagram) -> Result<(), SendQueueError> {
-        let datagram = Packets::OnlinePacket(OnlinePackets::Datagram(datagram));
-        if let Ok(buf) = datagram.write_to_bytes() {
-            return self.send_raw(buf.as_slice()).await;
-        } else {
-            return Err(SendQueueError::SendError);
+    /// Assigns the channel information for `ordering` to a set.
+    fn order_set(&mut self, set: DataSet, ordering: SendOrdering, channel: Option<u16>) -> DataSet {
+        let id = channel.unwrap_or(0);
+        let (ord_seq, ord_idx) = self.ord_chans.entry(id).or_insert((0, 0));
+
+        match ordering {
+            SendOrdering::None => set,
+            SendOrdering::Ordered => {
+                let set = set.with_ordered(id, *ord_idx);
+                *ord_idx = ord_idx.wrapping_add(1);
+                set
+            }
+            SendOrdering::Sequenced => {
+                let set = set.with_sequenced(id, *ord_seq);
+                *ord_seq = ord_seq.wrapping_add(1);
+                set
+            }
         }
     }
 
-    pub async fn send_raw(&mut self, packet: &[u8]) -> Result<(), SendQueueError> {
-        if let Err(e) = self.socket.send_to(packet, &self.address).await {
-            return Err(SendQueueError::SendError);
+    /// A wrapper to send a single set over the network.
+    /// This is used for sets that skip the queue, IE: `Low` and `Immediate` priorities.
+    fn send_set(&mut self, set: DataSet, now: u64) -> Result<(), SendQueueError> {
+        self.send_sets(vec![set], now)
+    }
+
+    /// Packs `sets` into a single datagram and sends it.
+    /// This will automatically set the sequence number of the datagram, the reliable
+    /// sequence number is assigned when the set is queued.
+    ///
+    /// Reliable sets are stored in the recovery queue until they are acknowledged,
+    /// the datagram is tracked so it's sets can be recovered if it is lost.
+    fn send_sets(&mut self, sets: Vec<DataSet>, now: u64) -> Result<(), SendQueueError> {
+        let sequence = self.seq.next();
+        let reliable = sets
+            .iter()
+            .filter_map(|set| set.reliable_seq.as_ref().map(|seq| seq.0))
+            .collect::<Vec<u32>>();
+
+        // keep a copy of the reliable sets, in case they need to be resent.
+        let mut retries = 0;
+        for set in sets.iter() {
+            if let Some(ref seq) = set.reliable_seq {
+                match self.ack.entry(seq.0) {
+                    Some(entry) => retries = retries.max(entry.retries),
+                    None => self
+                        .ack
+                        .insert_id(seq.0, set.payload.data.len(), set.clone(), now),
+                }
+            }
+        }
+
+        let datagram = Datagram::new().with_sequence(sequence).with_sets(sets);
+
+        let buf = self.encode_online(OnlinePackets::Datagram(datagram), Some(sequence), now)?;
+        let size = buf.len();
+
+        if reliable.len() > 0 {
+            self.in_flight.insert(
+                sequence,
+                InFlight {
+                    sent: now,
+                    size,
+                    sets: reliable,
+                    retries,
+                },
+            );
+            self.congestion.on_sent(size);
         } else {
-            return Ok(());
+            self.congestion.on_sent_unreliable(size);
         }
+
+        self.outgoing.push_back(buf);
+        Ok(())
     }
 
-    pub async fn update(&mut self) {
-        // high priority packets sent FIRST
-        for pk in self
-            .queue
-            .get_mut(&SendPriority::High)
-            .unwrap()
-            .drain(..)
-            .collect::<Vec<DataSet>>()
-        {
-            if let Err(_) = self.send_set(pk).await {
-                println!("failed to send high priority packet");
+    /// Encodes an online packet, sealing it if the session is encrypted.
+    /// Datagrams are sealed with their own sequence, anything else uses the control sequence.
+    fn encode_online(
+        &mut self,
+        packet: OnlinePackets,
+        sequence: Option<u32>,
+        now: u64,
+    ) -> Result<Vec<u8>, SendQueueError> {
+        let packet = match self.sealing_key {
+            Some(ref mut key) => {
+                let plaintext = match packet.write_to_bytes() {
+                    Ok(buf) => buf,
+                    Err(_) => return Err(SendQueueError::SendError),
+                };
+
+                let sealed = match sequence {
+                    Some(sequence) => {
+                        key.seal(SealedKind::Datagram, sequence, plaintext.as_slice(), now)
+                    }
+                    None => key.seal(
+                        SealedKind::Control,
+                        self.control_seq.next(),
+                        plaintext.as_slice(),
+                        now,
+                    ),
+                };
+
+                match sealed {
+                    Ok(sealed) => OnlinePackets::Sealed(sealed),
+                    Err(_) => return Err(SendQueueError::SendError),
+                }
+            }
+            None => packet,
+        };
+
+        match Packets::OnlinePacket(packet).write_to_bytes() {
+            Ok(buf) => Ok(buf.as_slice().to_vec()),
+            Err(_) => Err(SendQueueError::SendError),
+        }
+    }
+
+    /// Sends an online packet that is not a datagram, IE: `Ack`, `Ping` or `Pong`.
+    /// These are not reliable, but are sealed if the session is encrypted.
+    pub fn send_online(&mut self, packet: OnlinePackets, now: u64) -> Result<(), SendQueueError> {
+        let buf = self.encode_online(packet, None, now)?;
+        self.send_raw(&buf);
+        Ok(())
+    }
+
+    /// The queue the next set should be taken from.
+    /// Lost sets are resent first, then `High` and finally `Medium` priority sets.
+    fn next_queue(&mut self) -> Option<&mut VecDeque<DataSet>> {
+        if !self.resend.is_empty() {
+            return Some(&mut self.resend);
+        }
+
+        for priority in [SendPriority::High, SendPriority::Medium] {
+            if self.queue.get(&priority).map_or(false, |q| !q.is_empty()) {
+                return self.queue.get_mut(&priority);
+            }
+        }
+
+        None
+    }
+
+    /// Packs as many queued sets as the mtu allows into each datagram, and sends them until
+    /// the queue is empty, or the congestion window or the pacer stops us.
+    /// Sets that can't be sent stay queued, in order, for the next tick.
+    /// If a datagram can't be encoded, flushing stops and the error is returned.
+    fn flush_queue(&mut self, now: u64) -> Result<(), SendQueueError> {
+        let budget = (self.mtu_size as usize).saturating_sub(IP_UDP_HEADER_SIZE as usize);
+
+        loop {
+            let mut sets = Vec::new();
+            let mut size = DATAGRAM_HEADER_SIZE;
+            if self.sealing_key.is_some() {
+                size += SEAL_OVERHEAD as usize;
+            }
+
+            loop {
+                let set_size = match self.next_queue() {
+                    None => break,
+                    Some(queue) => match queue.front().unwrap().write_to_bytes() {
+                        Ok(buf) => buf.as_slice().len(),
+                        Err(_) => {
+                            // this set can never be sent.
+                            queue.pop_front();
+                            continue;
+                        }
+                    },
+                };
+
+                // a set that doesn't fit in an empty datagram is sent on it's own.
+                if sets.len() > 0 && size + set_size > budget {
+                    break;
+                }
+
+                if !self.congestion.can_send(size + set_size) {
+                    break;
+                }
+
+                sets.push(self.next_queue().unwrap().pop_front().unwrap());
+                size += set_size;
+            }
+
+            if sets.len() == 0 {
+                break;
             }
+
+            self.send_sets(sets, now)?;
         }
-        // medium priority packets sent SECOND
-        for pk in self
-            .queue
-            .get_mut(&SendPriority::Medium)
-            .unwrap()
-            .drain(..)
-            .collect::<Vec<DataSet>>()
-        {
-            if let Err(_) = self.send_set(pk).await {
-                println!("failed to send medium priority packet");
+
+        Ok(())
+    }
+
+    pub fn send_datagram(&mut self, datagram: Datagram, now: u64) -> Result<(), SendQueueError> {
+        let sequence = datagram.sequence;
+        let buf = self.encode_online(OnlinePackets::Datagram(datagram), Some(sequence), now)?;
+        self.send_raw(&buf);
+        Ok(())
+    }
+
+    /// Queues an already encoded packet to be sent as is, IE: an offline `Disconnect`.
+    pub fn send_raw(&mut self, packet: &[u8]) {
+        self.outgoing.push_back(packet.to_vec());
+    }
+
+    /// Takes the next encoded packet that should be sent to the peer.
+    pub fn poll_transmit(&mut self) -> Option<Vec<u8>> {
+        self.outgoing.pop_front()
+    }
+
+    /// Requeues the sets of a lost datagram that have not been acknowledged yet.
+    ///
+    /// Sets that have been resent `max_retries` times are dropped, this is reported
+    /// by the next call to `update`.
+    fn recover(&mut self, datagram: InFlight, now: u64) {
+        for seq in datagram.sets {
+            let retries = match self.ack.entry(seq) {
+                Some(entry) => entry.retries,
+                // this set was acknowledged in another datagram.
+                None => continue,
+            };
+
+            if retries >= self.max_retries {
+                self.ack.discard(seq);
+                self.settle(seq, false);
+                self.retries_exceeded = true;
+                continue;
+            }
+
+            self.ack.mark_resent(seq, now);
+            if let Some(entry) = self.ack.entry(seq) {
+                self.resend.push_back(entry.item.clone());
             }
         }
+    }
+
+    /// Sends all queued packets, and resends any packets that have not been
+    /// acknowledged within the retransmission timeout.
+    ///
+    /// This will return `SendQueueError::RetryLimitExceeded` if a packet was resent
+    /// more than `max_retries` times, or `SendQueueError::SendError` if a datagram
+    /// could not be encoded or sealed.
+    pub fn update(&mut self, now: u64) -> Result<(), SendQueueError> {
+        self.congestion.refill(self.rtt.srtt(), now);
+
+        // recover anything that timed out, this is resent before anything else.
+        let rtt = self.rtt;
+        let expired = self
+            .in_flight
+            .iter()
+            .filter(|(_, datagram)| datagram.sent + rtt.backoff(datagram.retries) <= now)
+            .map(|(seq, _)| *seq)
+            .collect::<Vec<u32>>();
+
+        if expired.len() > 0 {
+            self.congestion.on_timeout(now);
+        }
 
-        // clear old ACKs
-        for pk in self.ack.flush_old(1000).drain(..) {
-            if let Err(_) = self.send_datagram(pk).await {
-                println!("failed to send ack packet");
+        for seq in expired {
+            if let Some(datagram) = self.in_flight.remove(&seq) {
+                self.congestion.on_discard(datagram.size);
+                self.recover(datagram, now);
             }
         }
+
+        let flushed = self.flush_queue(now);
+
+        if std::mem::take(&mut self.retries_exceeded) {
+            return Err(SendQueueError::RetryLimitExceeded);
+        }
+
+        flushed
     }
-}
 
-impl Acknowledgeable for SendQueue {
-    type NackItem = Datagram;
+    /// Acknowledges every datagram in `ack`, recieved at `now`.
+    pub fn ack(&mut self, ack: Acknowledgement, now: u64) {
+        // we are ackowledging a datagram!
+        // every set that was inside of it has been recieved.
+        ack.seqs().into_iter().for_each(|seq| {
+            if let Some(datagram) = self.in_flight.remove(&seq) {
+                self.congestion.on_ack(datagram.size);
+
+                // resent datagrams are ambiguous, so no sample is taken (Karn's algorithm).
+                if datagram.retries == 0 {
+                    self.rtt.update(now.saturating_sub(datagram.sent));
+                }
 
-    fn ack(&mut self, ack: crate::net::udp::proto::online::ack::Acknowledgement) {
-        // we are ackowledging a packet!
-        // remove each item from the queue
-        ack.seqs.into_iter().for_each(|seq| {
-            if let Err(_) = self.ack.remove(seq) {};
+                for set in datagram.sets {
+                    self.ack.acknowledge(set);
+                    self.settle(set, true);
+                }
+            }
         });
     }
 
-    fn nack(
-        &mut self,
-        nack: crate::net::udp::proto::online::ack::Acknowledgement,
-    ) -> Vec<Self::NackItem> {
-        nack.seqs
-            .into_iter()
-            .filter_map(|seq| {
-                if let Ok(v) = self.ack.get(seq) {
-                    Some(v.clone())
-                } else {
-                    None
-                }
-            })
-            .collect()
+    /// Requeues every set from the missing datagrams that has not been acknowledged.
+    /// The sets will be resent in new datagrams on the next update, they are returned
+    /// for informational purposes.
+    pub fn nack(&mut self, nack: Acknowledgement, now: u64) -> Vec<DataSet> {
+        let start = self.resend.len();
+
+        for seq in nack.seqs() {
+            if let Some(datagram) = self.in_flight.remove(&seq) {
+                // the peer is missing this packet, the network is likely congested.
+                self.congestion.on_loss(datagram.sent, now);
+                self.congestion.on_discard(datagram.size);
+                self.recover(datagram, now);
+            }
+        }
+
+        self.resend.iter().skip(start).cloned().collect()
     }
 }
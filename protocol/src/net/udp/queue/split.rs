@@ -1,10 +1,41 @@
 use std::collections::HashMap;
 
-use crate::net::udp::proto::{
-    online::dataset::{DataBit, DataSet, SplitInfo},
-    MAX_PROTO_OVERHEAD,
+use crate::{
+    net::udp::proto::{
+        online::dataset::{DataBit, DataSet, SplitInfo},
+        MAX_PROTO_OVERHEAD,
+    },
+    MAX_SPLIT_SIZE,
 };
 
+/// The amount of splits that can be reassembled at the same time.
+pub const MAX_CONCURRENT_SPLITS: usize = 64;
+/// The amount of bytes that can be buffered by partial splits.
+pub const MAX_SPLIT_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// How long a split has to be completed before it is discarded, in milliseconds.
+pub const SPLIT_TIMEOUT: u64 = 30_000;
+
+/// Limits how many resources a peer can use with partial splits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitLimits {
+    /// The amount of splits that can be reassembled at the same time.
+    pub max_splits: usize,
+    /// The amount of bytes that can be buffered by partial splits.
+    pub max_buffer_size: usize,
+    /// How long a split has to be completed before it is discarded, in milliseconds.
+    pub timeout: u64,
+}
+
+impl Default for SplitLimits {
+    fn default() -> Self {
+        Self {
+            max_splits: MAX_CONCURRENT_SPLITS,
+            max_buffer_size: MAX_SPLIT_BUFFER_SIZE,
+            timeout: SPLIT_TIMEOUT,
+        }
+    }
+}
+
 /// A reliable split packet queue.
 /// This struct will handle splitting packets into multiple packets, as well as reassembling them.
 pub struct SplitQueue {
@@ -16,6 +47,11 @@ pub struct SplitQueue {
     /// Hashmap represends the following values:
     /// (size, Vec<DataSet>)
     splits: HashMap<u16, (u32, Vec<DataSet>)>,
+    /// When each split we are recieving was started, in milliseconds.
+    started: HashMap<u16, u64>,
+    /// The amount of payload bytes buffered by all splits.
+    buffered: usize,
+    limits: SplitLimits,
 }
 
 impl SplitQueue {
@@ -23,34 +59,58 @@ impl SplitQueue {
         Self {
             id: 0,
             splits: HashMap::new(),
+            started: HashMap::new(),
+            buffered: 0,
+            limits: SplitLimits::default(),
         }
     }
 
+    /// Sets the limits for splits that are inserted into this queue.
+    pub fn with_limits(mut self, limits: SplitLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    fn track(&mut self, sets: &[DataSet]) {
+        self.buffered += sets.iter().map(|s| s.payload.data.len()).sum::<usize>();
+    }
+
+    fn untrack(&mut self, id: u16, sets: &[DataSet]) {
+        self.started.remove(&id);
+        self.buffered = self
+            .buffered
+            .saturating_sub(sets.iter().map(|s| s.payload.data.len()).sum::<usize>());
+    }
+
     /// This will add a buffer to the split queue and split it in the process, returning it's ID.
     pub fn add(&mut self, buffer: &[u8], mtu: u16) -> Result<u16, SplitQueueError> {
-        self.id += self.id.wrapping_add(1);
+        self.id = self.id.wrapping_add(1);
 
         let id = self.id as u16;
 
-        if self.splits.contains_key(&id) {
-            self.splits.remove(&id);
-        }
+        let _ = self.remove(&id);
 
-        if let Ok(splits) = Self::split(buffer, id, mtu) {
-            self.splits.insert(id, (splits.len() as u32, splits));
-            return Ok(id);
-        }
-
-        return Err(SplitQueueError::NotRequired);
+        let splits = Self::split(buffer, id, mtu)?;
+        self.track(&splits);
+        self.splits.insert(id, (splits.len() as u32, splits));
+        Ok(id)
     }
 
     /// This inserts a split into the split queue
     /// This will return a tuple of the size of the split, and the current index of the split.
     /// IE: (size, index)
     ///
-    /// This is primarily used to digest split payloads coming in.
-    pub fn insert(&mut self, set: DataSet) -> Result<(u32, u32), SplitQueueError> {
+    /// This is primarily used to digest split payloads coming in, `now` is the time
+    /// the set was recieved in milliseconds.
+    /// If the split would exceed the queue's limits, `SplitQueueError::LimitExceeded` is returned.
+    pub fn insert(&mut self, set: DataSet, now: u64) -> Result<(u32, u32), SplitQueueError> {
         if let Some(split_info) = set.split.as_ref() {
+            let payload_size = set.payload.data.len();
+
+            if self.buffered + payload_size > self.limits.max_buffer_size {
+                return Err(SplitQueueError::LimitExceeded);
+            }
+
             // check if we have this split already, if we don't insert it.
             // if we do, process it.
             if let Some((size, splits)) = self.splits.get_mut(&split_info.id) {
@@ -68,14 +128,25 @@ impl SplitQueue {
                     // insert the split into the splits vec.
                     let ret = (*size, split_info.index);
                     splits.push(set);
+                    self.buffered += payload_size;
                     return Ok(ret);
                 }
             } else {
+                if split_info.index >= split_info.total {
+                    return Err(SplitQueueError::IndexOutOfBounds);
+                }
+
+                if self.splits.len() >= self.limits.max_splits {
+                    return Err(SplitQueueError::LimitExceeded);
+                }
+
                 // This is a new split, insert it.
                 let (size, mut splits) = (split_info.total, Vec::<DataSet>::new());
                 let (id, index) = (split_info.id, split_info.index);
                 splits.push(set);
 
+                self.track(&splits);
+                self.started.insert(id, now);
                 self.splits.insert(id, (size, splits));
                 return Ok((size, index));
             }
@@ -85,20 +156,16 @@ impl SplitQueue {
     }
 
     pub fn split_insert(&mut self, buffer: &[u8], mtu: u16) -> Result<u16, SplitQueueError> {
-        self.id += self.id.wrapping_add(1);
+        self.id = self.id.wrapping_add(1);
 
         let id = self.id;
 
-        if self.splits.contains_key(&id) {
-            self.splits.remove(&id);
-        }
-
-        if let Ok(packets) = Self::split(buffer, id as u16, mtu) {
-            self.splits.insert(id, (packets.len() as u32, packets));
-            return Ok(id);
-        }
+        let _ = self.remove(&id);
 
-        return Err(SplitQueueError::NotRequired);
+        let packets = Self::split(buffer, id, mtu)?;
+        self.track(&packets);
+        self.splits.insert(id, (packets.len() as u32, packets));
+        Ok(id)
     }
 
     /// Attempts to join all splits into a single buffer.
@@ -121,7 +188,7 @@ impl SplitQueue {
                     buf.extend_from_slice(&split.payload.data);
                 }
 
-                self.splits.remove(&id);
+                let _ = self.remove(&id);
 
                 return Ok(buf);
             } else {
@@ -132,10 +199,17 @@ impl SplitQueue {
         return Err(SplitQueueError::InvalidIndex);
     }
 
+    /// Splits a buffer into sets that fit in the mtu.
+    /// Fails with `TooLarge` if the buffer needs more than `MAX_SPLIT_SIZE` parts,
+    /// the peer would refuse to reassemble it.
     pub fn split(buffer: &[u8], id: u16, mtu: u16) -> Result<Vec<DataSet>, SplitQueueError> {
         let max_mtu = mtu - MAX_PROTO_OVERHEAD;
 
         if buffer.len() > max_mtu.into() {
+            if buffer.len().div_ceil(max_mtu.into()) > MAX_SPLIT_SIZE.into() {
+                return Err(SplitQueueError::TooLarge);
+            }
+
             let splits = buffer
                 .chunks(max_mtu.into())
                 .map(|c| c.to_vec())
@@ -187,15 +261,40 @@ impl SplitQueue {
 
     pub fn remove(&mut self, id: &u16) -> Result<(u32, Vec<DataSet>), SplitQueueError> {
         if let Some(split) = self.splits.remove(id) {
+            self.untrack(*id, &split.1);
             return Ok(split);
         }
 
         return Err(SplitQueueError::InvalidIndex);
     }
 
+    /// Discards every split that has not been completed within the timeout.
+    /// Returns the ids of the discarded splits.
+    pub fn expire(&mut self, now: u64) -> Vec<u16> {
+        let expired = self
+            .started
+            .iter()
+            .filter(|(_, started)| *started + self.limits.timeout <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<u16>>();
+
+        for id in expired.iter() {
+            let _ = self.remove(id);
+        }
+
+        expired
+    }
+
     pub fn clear(&mut self) {
         self.id = 0;
         self.splits.clear();
+        self.started.clear();
+        self.buffered = 0;
+    }
+
+    /// The amount of payload bytes buffered by all splits.
+    pub fn buffered(&self) -> usize {
+        self.buffered
     }
 
     pub fn len(&self) -> usize {
@@ -223,4 +322,9 @@ pub enum SplitQueueError {
     /// This error occurs when you try to process a split that has a index out of bounds.
     /// For example, the split size is 10, and you try to insert an index of 11.
     IndexOutOfBounds,
+    /// This error occurs when a split would exceed the `SplitLimits` of the queue.
+    /// IE: Too many splits are being reassembled, or too many bytes are buffered.
+    LimitExceeded,
+    /// This error occurs when a buffer would be split into more than `MAX_SPLIT_SIZE` parts.
+    TooLarge,
 }
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use crate::util::current_epoch;
+use crate::util::{current_epoch, serial};
 
 pub struct Window {
     window: (u32, u32),
@@ -17,10 +17,26 @@ impl Window {
         }
     }
 
+    /// A window that starts at `start`, used to test the window wrapping.
+    #[cfg(test)]
+    pub(crate) fn starting_at(start: u32) -> Self {
+        Self {
+            window: (start, start.wrapping_add(2048)),
+            size: 2048,
+            recv: HashMap::new(),
+        }
+    }
+
     /// Insert a sequence number into the window.
-    /// Returns true if the sequence number is in the window.
+    /// Returns true if the sequence number is in the window, and has not been recieved before.
+    /// The window may wrap around `u32::MAX`.
     pub fn insert(&mut self, seq: u32) -> bool {
-        if seq < self.window.0 || seq > self.window.1 {
+        if !serial::in_range(seq, self.window.0, self.window.1) {
+            return false;
+        }
+
+        // this is a duplicate (or a replay).
+        if self.recv.contains_key(&seq) {
             return false;
         }
 
@@ -33,6 +49,20 @@ impl Window {
         return true;
     }
 
+    /// Insert a sequence number into the window, moving the window forward if it is past the end.
+    /// Unlike `insert`, the window never waits for a missing sequence, this is used for packets
+    /// that are never resent, where a lost sequence would stall the window forever.
+    /// Returns true if the sequence number has not been recieved before, and is not behind the window.
+    pub fn insert_sliding(&mut self, seq: u32) -> bool {
+        if serial::ge(seq, self.window.1) {
+            self.window.1 = seq.wrapping_add(1);
+            self.window.0 = self.window.1.wrapping_sub(self.size);
+            self.cleanup();
+        }
+
+        self.insert(seq)
+    }
+
     pub fn adjust(&mut self) {
         while self.recv.contains_key(&self.window.0) {
             self.recv.remove(&self.window.0);
@@ -49,13 +79,13 @@ impl Window {
     }
 
     pub fn missing(&self) -> Vec<u32> {
-        (self.window.0..self.window.1)
-            .into_iter()
+        serial::range(self.window.0, self.window.1)
             .filter(|seq| !self.recv.contains_key(seq))
-            .map(|seq| seq)
             .collect()
     }
 
+    /// The window as a range, note that `end` may be lower than `start`
+    /// if the window wraps, use `serial` to compare against it.
     pub fn window(&self) -> std::ops::Range<u32> {
         self.window.0..self.window.1
     }
@@ -63,7 +93,7 @@ impl Window {
     pub fn cleanup(&mut self) {
         // clean up old packets.
         self.recv
-            .retain(|seq, _| *seq > self.window.0 && *seq < self.window.1);
+            .retain(|seq, _| serial::in_range(*seq, self.window.0, self.window.1));
     }
 
     /// Purge packets that are older than 60 seconds.
@@ -72,6 +102,55 @@ impl Window {
 
         // store now so it's constant for the loop.
         let now = current_epoch();
-        self.recv.retain(|_, v| *v > (now + 60));
+        self.recv.retain(|_, v| (*v + 60) > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Window;
+
+    #[test]
+    fn insert_across_wrap() {
+        let mut window = Window::starting_at(u32::MAX - 1);
+
+        assert!(window.insert(u32::MAX - 1));
+        assert_eq!(window.window().start, u32::MAX);
+
+        // 0 comes after u32::MAX, so it is ahead of the window start.
+        assert!(window.insert(0));
+        assert!(!window.insert(0));
+        assert_eq!(window.missing()[0], u32::MAX);
+        assert!(!window.missing().contains(&0));
+
+        assert!(window.insert(u32::MAX));
+        assert_eq!(window.window().start, 1);
+        assert_eq!(window.window().end, 1 + 2048);
+
+        // anything before the window, or past the end of it is rejected.
+        assert!(!window.insert(u32::MAX - 1));
+        assert!(!window.insert(u32::MAX));
+        assert!(!window.insert(1 + 2048));
+        assert!(window.insert(2048));
+    }
+
+    #[test]
+    fn insert_sliding_skips_gaps() {
+        let mut window = Window::starting_at(u32::MAX - 1);
+
+        assert!(window.insert_sliding(u32::MAX - 1));
+        assert!(!window.insert_sliding(u32::MAX - 1));
+
+        // far past the end, the window moves so the sequence is it's last entry.
+        assert!(window.insert_sliding(4000));
+        assert_eq!(window.window().end, 4001);
+        assert_eq!(window.window().start, 4001 - 2048);
+        assert!(!window.insert_sliding(4000));
+
+        // older sequences are accepted once, if they are still within the window.
+        assert!(window.insert_sliding(3000));
+        assert!(!window.insert_sliding(3000));
+        assert!(!window.insert_sliding(u32::MAX));
+        assert!(!window.insert_sliding(4001 - 2049));
     }
 }
@@ -0,0 +1,586 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{sync::Notify, time::Instant};
+
+use super::transport::DatagramTransport;
+
+/// The conditions every datagram on a `SimNetwork` is subject to.
+/// The default is a perfect link, with no loss and no latency.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConditions {
+    /// The chance a datagram is dropped, between 0 and 1.
+    pub loss: f64,
+    /// The chance a datagram is delivered twice, between 0 and 1.
+    pub duplicate: f64,
+    /// The chance a datagram is held back, so datagrams sent after it arrive first.
+    pub reorder: f64,
+    /// How long a datagram is held back when it is reordered.
+    pub reorder_delay: Duration,
+    /// The one way delay of every datagram.
+    pub latency: Duration,
+    /// A random delay, up to this amount, added to the latency of every datagram.
+    pub jitter: Duration,
+    /// How many bytes each endpoint can send per second, `None` is unlimited.
+    /// Datagrams queue up behind each other when the limit is reached.
+    pub bandwidth: Option<u64>,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            bandwidth: None,
+        }
+    }
+}
+
+impl LinkConditions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    pub fn with_duplicate(mut self, duplicate: f64) -> Self {
+        self.duplicate = duplicate;
+        self
+    }
+
+    pub fn with_reorder(mut self, reorder: f64, delay: Duration) -> Self {
+        self.reorder = reorder;
+        self.reorder_delay = delay;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth = Some(bytes_per_sec);
+        self
+    }
+}
+
+/// Counters for everything that happened on a `SimNetwork`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub delivered: u64,
+}
+
+/// A datagram waiting to be delivered, ordered by when it arrives.
+/// `id` breaks ties, so datagrams that arrive at the same time are delivered in the order they were sent.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct InTransit {
+    arrival: Instant,
+    id: u64,
+    from: SocketAddr,
+    buf: Vec<u8>,
+}
+
+struct Endpoint {
+    inbox: BinaryHeap<Reverse<InTransit>>,
+    notify: Arc<Notify>,
+    /// When the endpoint's link is free to send again, used for the bandwidth limit.
+    busy_until: Instant,
+}
+
+struct SimState {
+    rng: StdRng,
+    conditions: LinkConditions,
+    endpoints: HashMap<SocketAddr, Endpoint>,
+    next_id: u64,
+    stats: LinkStats,
+}
+
+/// An in-process network, every `SimSocket` bound to it can send datagrams to the others.
+///
+/// Every decision (loss, duplication, reordering and jitter) is drawn from a rng seeded with
+/// the given seed, so the same sends produce the same deliveries. Delays use the tokio clock,
+/// so a runtime with a paused clock (`tokio::time::pause`) replays a run exactly, without waiting.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimNetwork {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimState {
+                rng: StdRng::seed_from_u64(seed),
+                conditions,
+                endpoints: HashMap::new(),
+                next_id: 0,
+                stats: LinkStats::default(),
+            })),
+        }
+    }
+
+    /// Binds a new socket to `addr`.
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<Arc<SimSocket>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.endpoints.contains_key(&addr) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                "Address is already bound on this network.",
+            ));
+        }
+
+        let notify = Arc::new(Notify::new());
+        state.endpoints.insert(
+            addr,
+            Endpoint {
+                inbox: BinaryHeap::new(),
+                notify: notify.clone(),
+                busy_until: Instant::now(),
+            },
+        );
+
+        Ok(Arc::new(SimSocket {
+            addr,
+            notify,
+            state: self.state.clone(),
+        }))
+    }
+
+    /// Changes the conditions of the network, this applies to datagrams sent from now on.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state.lock().unwrap().conditions = conditions;
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+/// A socket bound to a `SimNetwork`.
+/// The socket is unbound when it is dropped.
+pub struct SimSocket {
+    addr: SocketAddr,
+    notify: Arc<Notify>,
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimSocket {
+    fn transmit(&self, buf: &[u8], to: SocketAddr) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let conditions = state.conditions;
+        let now = Instant::now();
+
+        state.stats.sent += 1;
+
+        // the datagram takes up the sender's link, even if it is lost on the way.
+        let mut departure = now;
+        if let Some(bandwidth) = conditions.bandwidth {
+            if let Some(sender) = state.endpoints.get_mut(&self.addr) {
+                let start = sender.busy_until.max(now);
+                let transmission =
+                    Duration::from_secs_f64(buf.len() as f64 / bandwidth.max(1) as f64);
+                sender.busy_until = start + transmission;
+                departure = sender.busy_until;
+            }
+        }
+
+        if state.rng.gen::<f64>() < conditions.loss {
+            state.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if state.rng.gen::<f64>() < conditions.duplicate {
+            state.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut delay = conditions.latency;
+
+            if !conditions.jitter.is_zero() {
+                delay += Duration::from_nanos(
+                    state.rng.gen_range(0..=conditions.jitter.as_nanos() as u64),
+                );
+            }
+
+            if state.rng.gen::<f64>() < conditions.reorder {
+                state.stats.reordered += 1;
+                delay += conditions.reorder_delay;
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+
+            // like udp, datagrams sent to an address nobody is bound to are lost.
+            let receiver = match state.endpoints.get_mut(&to) {
+                Some(receiver) => receiver,
+                None => {
+                    state.stats.dropped += 1;
+                    continue;
+                }
+            };
+
+            receiver.inbox.push(Reverse(InTransit {
+                arrival: departure + delay,
+                id,
+                from: self.addr,
+                buf: buf.to_vec(),
+            }));
+            receiver.notify.notify_one();
+        }
+    }
+}
+
+#[async_trait]
+impl DatagramTransport for SimSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        self.transmit(buf, addr);
+        Ok(buf.len())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        loop {
+            let next_arrival = {
+                let mut guard = self.state.lock().unwrap();
+                let state = &mut *guard;
+                let endpoint = match state.endpoints.get_mut(&self.addr) {
+                    Some(endpoint) => endpoint,
+                    None => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotConnected,
+                            "Socket is no longer bound.",
+                        ));
+                    }
+                };
+
+                match endpoint.inbox.peek() {
+                    Some(Reverse(datagram)) if datagram.arrival <= Instant::now() => {
+                        let Reverse(datagram) = endpoint.inbox.pop().unwrap();
+                        state.stats.delivered += 1;
+
+                        let len = datagram.buf.len().min(buf.len());
+                        buf[..len].copy_from_slice(&datagram.buf[..len]);
+                        return Ok((len, datagram.from));
+                    }
+                    Some(Reverse(datagram)) => Some(datagram.arrival),
+                    None => None,
+                }
+            };
+
+            match next_arrival {
+                Some(arrival) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(arrival) => {}
+                        _ = self.notify.notified() => {}
+                    }
+                }
+                None => self.notify.notified().await,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.endpoints.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use binary_util::interfaces::Reader;
+    use tokio::time::Instant;
+
+    use super::{LinkConditions, SimNetwork, SimSocket};
+    use crate::net::udp::{
+        connection::{Connection, ConnectionOptions, Event},
+        proto::{Packets, MAX_MTU_SIZE},
+        queue::send::{SendOrdering, SendPriority},
+        transport::DatagramTransport,
+    };
+
+    /// One side of a simulated connection.
+    /// The server's connection is accepted from the first `ConnectRequest` it recieves.
+    struct Peer {
+        socket: Arc<SimSocket>,
+        remote: SocketAddr,
+        conn: Option<Connection>,
+        connected: bool,
+        payloads: Vec<Vec<u8>>,
+    }
+
+    impl Peer {
+        fn drive(&mut self, now: u64, mut datagram: Option<&[u8]>) {
+            if self.conn.is_none() {
+                let buf = match datagram.take() {
+                    Some(buf) => buf,
+                    None => return,
+                };
+
+                // the cookie exchange is skipped, it happens before a connection exists.
+                if let Ok(Packets::ConnectRequest(request)) = Packets::read_from_slice(buf) {
+                    self.conn =
+                        Connection::accept(&request, buf.len(), ConnectionOptions::default(), now)
+                            .ok();
+                }
+            }
+
+            let conn = match self.conn {
+                Some(ref mut conn) => conn,
+                None => return,
+            };
+
+            let output = conn.drive(now, datagram);
+
+            for buf in output.transmits {
+                self.socket.transmit(&buf, self.remote);
+            }
+
+            for event in output.events {
+                match event {
+                    Event::Connected => self.connected = true,
+                    Event::Payload(payload) => self.payloads.push(payload),
+                    _ => {}
+                }
+            }
+        }
+
+        fn send(&mut self, payload: &[u8], ordering: SendOrdering, now: u64) {
+            self.conn
+                .as_mut()
+                .unwrap()
+                .send_with(payload, SendPriority::Medium, ordering, Some(1), now)
+                .unwrap();
+        }
+    }
+
+    fn millis(start: Instant) -> u64 {
+        start.elapsed().as_millis() as u64
+    }
+
+    /// Drives both peers until `done` returns true, or `limit` has passed.
+    async fn run(
+        client: &mut Peer,
+        server: &mut Peer,
+        start: Instant,
+        limit: Duration,
+        done: impl Fn(&Peer, &Peer) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + limit;
+        let (client_socket, server_socket) = (client.socket.clone(), server.socket.clone());
+        let (mut client_buf, mut server_buf) = (vec![0u8; 2048], vec![0u8; 2048]);
+
+        while !done(client, server) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            let timeout = [&client.conn, &server.conn]
+                .iter()
+                .filter_map(|conn| conn.as_ref().and_then(|conn| conn.poll_timeout()))
+                .min()
+                .map_or(deadline, |timeout| {
+                    (start + Duration::from_millis(timeout)).min(deadline)
+                });
+
+            tokio::select! {
+                biased;
+                recv = client_socket.recv_from(&mut client_buf) => {
+                    let (len, _) = recv.unwrap();
+                    client.drive(millis(start), Some(&client_buf[..len]));
+                }
+                recv = server_socket.recv_from(&mut server_buf) => {
+                    let (len, _) = recv.unwrap();
+                    server.drive(millis(start), Some(&server_buf[..len]));
+                }
+                _ = tokio::time::sleep_until(timeout) => {
+                    let now = millis(start);
+                    client.drive(now, None);
+                    server.drive(now, None);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Connects a client to a server over a new network, returning once both sides are connected.
+    async fn connect(conditions: LinkConditions, seed: u64) -> (SimNetwork, Peer, Peer, Instant) {
+        let network = SimNetwork::new(conditions, seed);
+        let client_addr: SocketAddr = "10.0.0.1:19132".parse().unwrap();
+        let server_addr: SocketAddr = "10.0.0.2:19132".parse().unwrap();
+        let start = Instant::now();
+
+        let mut client = Peer {
+            socket: network.bind(client_addr).unwrap(),
+            remote: server_addr,
+            conn: Some(Connection::connect(
+                MAX_MTU_SIZE,
+                ConnectionOptions::default(),
+                0,
+            )),
+            connected: false,
+            payloads: Vec::new(),
+        };
+        let mut server = Peer {
+            socket: network.bind(server_addr).unwrap(),
+            remote: client_addr,
+            conn: None,
+            connected: false,
+            payloads: Vec::new(),
+        };
+
+        client.drive(0, None);
+        let connected = run(
+            &mut client,
+            &mut server,
+            start,
+            Duration::from_secs(10),
+            |client, server| client.connected && server.connected,
+        )
+        .await;
+        assert!(connected, "seed {}: the handshake did not complete", seed);
+
+        (network, client, server, start)
+    }
+
+    /// `count` payloads that are each unique, every tenth one is large enough to be split.
+    fn payloads(count: u32) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| {
+                let len = if i % 10 == 0 { 5000 } else { 100 };
+                let mut payload = i.to_be_bytes().to_vec();
+                payload.extend((0..len).map(|b| (b as u32 + i) as u8));
+                payload
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivers_exactly_once_on_a_bad_link() {
+        let conditions = LinkConditions::new()
+            .with_loss(0.1)
+            .with_duplicate(0.05)
+            .with_reorder(0.1, Duration::from_millis(40))
+            .with_latency(Duration::from_millis(20), Duration::from_millis(10));
+
+        for seed in 0..4 {
+            let (network, mut client, mut server, start) = connect(conditions, seed).await;
+
+            // ordered from the client, unordered from the server.
+            let ordered = payloads(200);
+            let unordered = payloads(100);
+            let now = millis(start);
+
+            for payload in ordered.iter() {
+                client.send(payload, SendOrdering::Ordered, now);
+            }
+
+            for payload in unordered.iter() {
+                server.send(payload, SendOrdering::None, now);
+            }
+
+            let delivered = run(
+                &mut client,
+                &mut server,
+                start,
+                Duration::from_secs(60),
+                |client, server| {
+                    server.payloads.len() >= ordered.len()
+                        && client.payloads.len() >= unordered.len()
+                },
+            )
+            .await;
+            assert!(delivered, "seed {}: not every payload was delivered", seed);
+
+            // keep going for a while, so late duplicates have a chance to show up.
+            run(
+                &mut client,
+                &mut server,
+                start,
+                Duration::from_secs(2),
+                |_, _| false,
+            )
+            .await;
+
+            assert_eq!(server.payloads, ordered, "seed {}", seed);
+
+            let mut recieved = client.payloads.clone();
+            recieved.sort();
+            let mut expected = unordered.clone();
+            expected.sort();
+            assert_eq!(recieved, expected, "seed {}", seed);
+
+            let stats = network.stats();
+            assert!(stats.dropped > 0, "seed {}: {:?}", seed, stats);
+            assert!(stats.duplicated > 0, "seed {}: {:?}", seed, stats);
+            assert!(stats.reordered > 0, "seed {}: {:?}", seed, stats);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn same_seed_same_deliveries() {
+        let conditions = LinkConditions::new()
+            .with_loss(0.2)
+            .with_reorder(0.2, Duration::from_millis(40))
+            .with_latency(Duration::from_millis(20), Duration::from_millis(20));
+
+        let mut runs = Vec::new();
+
+        for _ in 0..2 {
+            let (network, mut client, mut server, start) = connect(conditions, 7).await;
+            let ordered = payloads(50);
+            let now = millis(start);
+
+            for payload in ordered.iter() {
+                client.send(payload, SendOrdering::Ordered, now);
+            }
+
+            run(
+                &mut client,
+                &mut server,
+                start,
+                Duration::from_secs(60),
+                |_, server| server.payloads.len() >= ordered.len(),
+            )
+            .await;
+
+            assert_eq!(server.payloads, ordered);
+            let stats = network.stats();
+            runs.push((stats.sent, stats.dropped, stats.reordered, stats.delivered));
+        }
+
+        assert_eq!(runs[0], runs[1]);
+    }
+}
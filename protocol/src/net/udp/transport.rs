@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+
+/// A connectionless transport that datagrams are sent over.
+///
+/// Connections are driven over this trait, so they can run over a real socket
+/// or over a simulated link, IE: `sim::SimNetwork`.
+/// Like UDP, a transport may drop, duplicate or reorder datagrams.
+#[async_trait]
+pub trait DatagramTransport: Send + Sync {
+    /// Sends a single datagram to `addr`, returning the amount of bytes sent.
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize>;
+
+    /// Recieves a single datagram, returning it's size and the address it was sent from.
+    /// If the datagram is larger than `buf`, the rest of it is discarded.
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+
+    /// The address this transport is bound to.
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+#[async_trait]
+impl DatagramTransport for tokio::net::UdpSocket {
+    async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        tokio::net::UdpSocket::send_to(self, buf, addr).await
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        tokio::net::UdpSocket::recv_from(self, buf).await
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+}
@@ -14,6 +14,21 @@ pub enum ChannelPackets {
     ChannelMessage(ChannelMessage),
 }
 
+impl ChannelPackets {
+    /// The channel this packet belongs to.
+    /// A failed join response has no channel.
+    pub fn channel_id(&self) -> Option<u16> {
+        match self {
+            ChannelPackets::ChannelJoinRequest(request) => Some(request.channel_id),
+            ChannelPackets::ChannelJoinResponse(response) => {
+                response.channel.as_ref().map(|channel| channel.id)
+            }
+            ChannelPackets::ChannelPermissionUpdate(update) => Some(update.channel_id),
+            ChannelPackets::ChannelMessage(message) => Some(message.channel_id),
+        }
+    }
+}
+
 #[derive(Debug, Clone, BinaryIo)]
 pub struct ChannelJoinRequest {
     /// The ID of the channel.
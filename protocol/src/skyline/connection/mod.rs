@@ -95,4 +95,8 @@ pub enum DisconnectReason {
     InvalidName,
     InvalidIdentifiers,
     InvalidProtocol,
+    /// The client exceeded a resource limit, IE: too many partial splits.
+    LimitExceeded,
+    /// Nothing was recieved from the client for too long.
+    TimedOut,
 }
@@ -29,3 +29,14 @@ pub enum SkylinePacket {
     LoginResponse(connection::LoginResponse),
     ChannelPacket(channel::packets::ChannelPackets),
 }
+
+impl SkylinePacket {
+    /// The channel this packet belongs to, if any.
+    /// The transport uses this to order packets per channel.
+    pub fn channel_id(&self) -> Option<u16> {
+        match self {
+            SkylinePacket::ChannelPacket(packet) => packet.channel_id(),
+            _ => None,
+        }
+    }
+}
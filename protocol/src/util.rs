@@ -14,6 +14,45 @@ pub fn current_epoch_ms() -> u128 {
         .as_millis()
 }
 
+/// Serial number arithmetic (RFC 1982) for `u32` sequences.
+///
+/// Sequences are generated with `wrapping_add`, so once a counter wraps
+/// `0` comes *after* `u32::MAX`. A sequence is considered less than another
+/// if it is less than half of the sequence space behind it.
+pub mod serial {
+    const HALF: u32 = 1 << 31;
+
+    /// Whether `a` comes before `b`.
+    pub fn lt(a: u32, b: u32) -> bool {
+        a != b && b.wrapping_sub(a) < HALF
+    }
+
+    /// Whether `a` comes before, or is equal to `b`.
+    pub fn le(a: u32, b: u32) -> bool {
+        a == b || lt(a, b)
+    }
+
+    /// Whether `a` comes after `b`.
+    pub fn gt(a: u32, b: u32) -> bool {
+        lt(b, a)
+    }
+
+    /// Whether `a` comes after, or is equal to `b`.
+    pub fn ge(a: u32, b: u32) -> bool {
+        le(b, a)
+    }
+
+    /// Whether `seq` is within `start..end`, the range may wrap.
+    pub fn in_range(seq: u32, start: u32, end: u32) -> bool {
+        seq.wrapping_sub(start) < end.wrapping_sub(start)
+    }
+
+    /// Every sequence in `start..end`, the range may wrap.
+    pub fn range(start: u32, end: u32) -> impl Iterator<Item = u32> {
+        (0..end.wrapping_sub(start)).map(move |offset| start.wrapping_add(offset))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SafeGenerator<T> {
     pub(crate) sequence: T,
@@ -33,11 +72,15 @@ where
 macro_rules! impl_gen {
     ($n: ty) => {
         impl SafeGenerator<$n> {
+            /// Returns the current value and advances the generator.
+            /// The first value handed out is always the default (0).
             pub fn next(&mut self) -> $n {
+                let current = self.sequence;
                 self.sequence = self.sequence.wrapping_add(1);
-                return self.sequence;
+                return current;
             }
 
+            /// The value that will be handed out by the next call to `next`.
             pub fn get(&self) -> $n {
                 self.sequence
             }
@@ -51,3 +94,40 @@ impl_gen!(u32);
 impl_gen!(u64);
 impl_gen!(u128);
 impl_gen!(usize);
+
+#[cfg(test)]
+mod tests {
+    use super::{serial, SafeGenerator};
+
+    #[test]
+    fn generator_wraps() {
+        let mut gen = SafeGenerator::<u32> {
+            sequence: u32::MAX - 1,
+        };
+
+        assert_eq!(gen.next(), u32::MAX - 1);
+        assert_eq!(gen.next(), u32::MAX);
+        assert_eq!(gen.next(), 0);
+        assert_eq!(gen.get(), 1);
+    }
+
+    #[test]
+    fn serial_compares_across_wrap() {
+        assert!(serial::lt(u32::MAX, 0));
+        assert!(serial::gt(0, u32::MAX));
+        assert!(serial::le(u32::MAX - 5, 3));
+        assert!(serial::ge(3, u32::MAX - 5));
+        assert!(!serial::lt(3, u32::MAX - 5));
+        assert!(!serial::lt(7, 7));
+
+        assert!(serial::in_range(0, u32::MAX - 1, 2));
+        assert!(serial::in_range(u32::MAX - 1, u32::MAX - 1, 2));
+        assert!(!serial::in_range(2, u32::MAX - 1, 2));
+        assert!(!serial::in_range(u32::MAX - 2, u32::MAX - 1, 2));
+
+        assert_eq!(
+            serial::range(u32::MAX - 1, 2).collect::<Vec<u32>>(),
+            vec![u32::MAX - 1, u32::MAX, 0, 1]
+        );
+    }
+}
@@ -65,11 +65,150 @@ pub enum TokenStrategy {
     UUID,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkOpts {
     pub mode: NetworkMode,
     #[serde(rename(serialize = "maxConnections", deserialize = "maxConnections"))]
     pub max_connections: u16,
+    /// Settings only used when the network mode is "udp".
+    #[serde(default)]
+    pub udp: UdpOpts,
+    /// Settings only used when the network mode is "tcp".
+    #[serde(default)]
+    pub tcp: TcpOpts,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TcpOpts {
+    /// The largest frame a client can send, in bytes.
+    #[serde(rename(serialize = "maxFrameSize", deserialize = "maxFrameSize"))]
+    pub max_frame_size: usize,
+    /// The amount of frames that can be queued for a client before sends wait.
+    #[serde(rename(serialize = "sendQueueSize", deserialize = "sendQueueSize"))]
+    pub send_queue_size: usize,
+    /// What to do with a client that isn't reading fast enough.
+    #[serde(rename(serialize = "slowReader", deserialize = "slowReader"))]
+    pub slow_reader: SlowReaderStrategy,
+    /// How long a send waits for a slow client before it is disconnected, in milliseconds.
+    #[serde(rename(serialize = "slowReaderTimeout", deserialize = "slowReaderTimeout"))]
+    pub slow_reader_timeout: u64,
+    /// How often heartbeats are sent to each client, in seconds.
+    #[serde(rename(serialize = "heartbeatInterval", deserialize = "heartbeatInterval"))]
+    pub heartbeat_interval: u16,
+    /// The amount of heartbeats a client can miss in a row before it is disconnected.
+    #[serde(rename(serialize = "maxMissedHeartbeats", deserialize = "maxMissedHeartbeats"))]
+    pub max_missed_heartbeats: u8,
+    pub tls: TlsOpts,
+}
+
+impl Default for TcpOpts {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 64 * 1024,
+            send_queue_size: 256,
+            slow_reader: SlowReaderStrategy::Disconnect,
+            slow_reader_timeout: 5000,
+            heartbeat_interval: 10,
+            max_missed_heartbeats: 3,
+            tls: TlsOpts::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsOpts {
+    /// Whether or not clients must connect with TLS.
+    pub enabled: bool,
+    /// The path to the PEM encoded certificate chain.
+    pub certificate: String,
+    /// The path to the PEM encoded private key.
+    pub key: String,
+    /// Whether or not clients are asked for a certificate.
+    #[serde(rename(serialize = "clientAuth", deserialize = "clientAuth"))]
+    pub client_auth: ClientAuthStrategy,
+    /// The path to the PEM encoded CA that client certificates are verified against.
+    #[serde(rename(serialize = "clientCa", deserialize = "clientCa"))]
+    pub client_ca: String,
+}
+
+impl Default for TlsOpts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            certificate: String::from("./cert.pem"),
+            key: String::from("./key.pem"),
+            client_auth: ClientAuthStrategy::None,
+            client_ca: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ClientAuthStrategy {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "optional")]
+    Optional,
+    #[serde(rename = "required")]
+    Required,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum SlowReaderStrategy {
+    #[serde(rename = "wait")]
+    Wait,
+    #[serde(rename = "disconnect")]
+    Disconnect,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UdpOpts {
+    /// The amount of times a packet is resent before the connection is dropped.
+    #[serde(rename(serialize = "maxRetries", deserialize = "maxRetries"))]
+    pub max_retries: u8,
+    /// How often a keepalive is sent to each client, in milliseconds.
+    pub keepalive: u64,
+    /// How long a client can be silent before it is timing out, in milliseconds.
+    pub timeout: u64,
+    /// How long a client can be silent before it is disconnected, in milliseconds.
+    #[serde(rename(serialize = "hardTimeout", deserialize = "hardTimeout"))]
+    pub hard_timeout: u64,
+}
+
+impl Default for UdpOpts {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            keepalive: 1000,
+            timeout: 10_000,
+            hard_timeout: 30_000,
+        }
+    }
+}
+
+/// The status sent to peers that ping the server without connecting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusOpts {
+    /// Whether or not the status is public, private deployments should disable this.
+    pub enabled: bool,
+    /// The display name of the server.
+    pub name: String,
+    /// The channels listed in the status.
+    pub channels: Vec<String>,
+}
+
+impl Default for StatusOpts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "Skyline".to_string(),
+            channels: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
@@ -95,6 +234,8 @@ pub struct Config {
     pub cluster: ClusterOpts,
     pub authorization: AuthOpts,
     pub network: NetworkOpts,
+    #[serde(default)]
+    pub status: StatusOpts,
 }
 
 impl Config {
@@ -121,7 +262,10 @@ impl Config {
             network: NetworkOpts {
                 mode: NetworkMode::Tcp,
                 max_connections: 0,
+                udp: UdpOpts::default(),
+                tcp: TcpOpts::default(),
             },
+            status: StatusOpts::default(),
         }
     }
 }
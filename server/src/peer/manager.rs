@@ -24,14 +24,11 @@ impl PeerManager {
         Ok(())
     }
 
-    pub fn remove_peer(&mut self, peer: Arc<Peer>) {
-        // when a peer is removed, we should close the connection
-        // todo: determine whether or not we should await the close
-        let _ = tokio::runtime::Handle::current().block_on(
-            peer.inner
-                .close(protocol::skyline::connection::DisconnectReason::Closed),
-        );
-        self.peers.remove(&peer.id);
+    /// Removes a peer, returning it if it was managed.
+    /// The peer's connection is not closed, the caller should close it once the manager is unlocked.
+    pub fn remove_peer(&mut self, peer: &Arc<Peer>) -> Option<Arc<Peer>> {
+        *peer.state.lock().unwrap() = super::PeerState::Disconnected;
+        self.peers.remove(&peer.id)
     }
 
     pub fn get_next_id(&self) -> PeerId {
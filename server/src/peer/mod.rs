@@ -6,7 +6,7 @@ use std::{
 };
 use tokio::{sync::Notify, task::JoinHandle};
 
-use crate::net::ConnAdapter;
+use crate::net::{ConnAdapter, DeliveryReceipt, SendOptions};
 
 pub enum PeerState {
     /// The peer is connected, and is ready to recieve packets.
@@ -91,6 +91,30 @@ impl Peer {
         Ok(())
     }
 
+    /// Forwards a packet to the connection adapter with the given delivery options.
+    /// Not every transport honors the options, see `ConnAdapter::send_with`.
+    pub async fn send_with(&self, packet: &SkylinePacket, options: SendOptions) -> std::io::Result<()> {
+        self.inner.send_with(packet, options).await?;
+        Ok(())
+    }
+
+    /// Sends a packet reliably, the receipt resolves once the peer has acknowledged it.
+    /// See `ConnAdapter::send_confirmed`.
+    pub async fn send_confirmed(
+        &self,
+        packet: &SkylinePacket,
+        options: SendOptions,
+        timeout: std::time::Duration,
+    ) -> std::io::Result<DeliveryReceipt> {
+        self.inner.send_confirmed(packet, options, timeout).await
+    }
+
+    /// What was negotiated during the TLS handshake, IE: the client's certificate.
+    /// This is `None` if the peer did not connect with TLS.
+    pub fn get_tls_session(&self) -> Option<crate::net::tcp::tls::TlsSession> {
+        self.inner.get_tls_session()
+    }
+
     async fn listen_for_close(&self, closer: Arc<Notify>) {
         let inner = self.inner.clone();
         tokio::task::spawn(async move {
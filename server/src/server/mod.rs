@@ -4,13 +4,15 @@ use tokio::sync::Mutex as TokioMutex;
 use tokio::{sync::Notify, task::JoinHandle};
 
 use crate::{
-    config::NetworkMode,
+    config::{ClientAuthStrategy, NetworkMode, SlowReaderStrategy},
     log_debug, log_error, log_notice, log_success, log_warn,
     net::NetworkInterface,
     peer::{Peer, PeerManager},
 };
 
 use colored::*;
+use protocol::net::udp::proto::offline::{ServerStatus, Transport};
+use protocol::skyline::connection::DisconnectReason;
 
 /// This is the main struct responsible for managing the server.
 /// It will handle all the connections, and will be responsible for
@@ -39,19 +41,89 @@ impl Server {
                 NetworkMode::Tcp => {
                     log_debug!("TCP mode selected, binding to {}", bind_address);
                     log_warn!("TCP mode selected by config file, with multiple clients (over 200) this may cause performance issues.");
-                    Arc::new(TokioMutex::new(Box::new(
-                        crate::net::tcp::TcpListener::new(bind_address.as_str()).await?,
-                    )))
+                    let slow_reader = match config.network.tcp.slow_reader {
+                        SlowReaderStrategy::Wait => crate::net::tcp::SlowReaderPolicy::Wait,
+                        SlowReaderStrategy::Disconnect => {
+                            crate::net::tcp::SlowReaderPolicy::Disconnect(
+                                std::time::Duration::from_millis(
+                                    config.network.tcp.slow_reader_timeout,
+                                ),
+                            )
+                        }
+                    };
+                    let options = crate::net::tcp::TcpOptions {
+                        max_frame_size: config.network.tcp.max_frame_size,
+                        send_queue_size: config.network.tcp.send_queue_size,
+                        slow_reader,
+                        heartbeat_interval: std::time::Duration::from_secs(
+                            config.network.tcp.heartbeat_interval as u64,
+                        ),
+                        max_missed_heartbeats: config.network.tcp.max_missed_heartbeats,
+                    };
+
+                    let mut listener = crate::net::tcp::TcpListener::new(bind_address.as_str())
+                        .await?
+                        .with_options(options);
+
+                    let tls = &config.network.tcp.tls;
+                    if tls.enabled {
+                        let client_auth = match tls.client_auth {
+                            ClientAuthStrategy::None => crate::net::tcp::tls::ClientAuth::None,
+                            ClientAuthStrategy::Optional => {
+                                crate::net::tcp::tls::ClientAuth::Optional
+                            }
+                            ClientAuthStrategy::Required => {
+                                crate::net::tcp::tls::ClientAuth::Required
+                            }
+                        };
+                        let client_ca = match tls.client_ca.as_str() {
+                            "" => None,
+                            path => Some(std::path::Path::new(path)),
+                        };
+
+                        log_debug!("TLS enabled, loading certificate from {}", tls.certificate);
+                        listener = listener.with_tls(crate::net::tcp::tls::server_config(
+                            &tls.certificate,
+                            &tls.key,
+                            client_ca,
+                            client_auth,
+                        )?);
+                    } else {
+                        log_warn!("TLS is disabled, tokens are sent in plaintext.");
+                    }
+
+                    Arc::new(TokioMutex::new(Box::new(listener)))
                 }
-                // NetworkMode::Udp => Arc::new(Box::new(crate::net::udp::UdpListener::new(address)?)),
-                _ => {
-                    log_error!(
-                        "Unsupported network mode: {}, attempting to start anyway...",
-                        mode
-                    );
-                    Arc::new(TokioMutex::new(Box::new(
-                        crate::net::NullInterface::new(bind_address.as_str()).await?,
-                    )))
+                NetworkMode::Udp => {
+                    log_debug!("UDP mode selected, binding to {}", bind_address);
+                    let options = crate::net::udp::UdpOptions {
+                        max_retries: config.network.udp.max_retries,
+                        keepalive_interval: std::time::Duration::from_millis(
+                            config.network.udp.keepalive,
+                        ),
+                        timeout: std::time::Duration::from_millis(config.network.udp.timeout),
+                        hard_timeout: std::time::Duration::from_millis(
+                            config.network.udp.hard_timeout,
+                        ),
+                        ..Default::default()
+                    };
+                    let mut listener =
+                        crate::net::udp::listener::UdpListener::new(bind_address.as_str())
+                            .await?
+                            .with_options(options);
+
+                    if config.status.enabled {
+                        listener = listener.with_status(ServerStatus {
+                            name: config.status.name.clone(),
+                            protocol_version: protocol::net::tcp::PROTOCOL_VERSION,
+                            peers: 0,
+                            max_peers: config.network.max_connections,
+                            channels: config.status.channels.clone(),
+                            transports: vec![Transport::Udp],
+                        });
+                    }
+
+                    Arc::new(TokioMutex::new(Box::new(listener)))
                 }
             },
             peer_manager: Arc::new(TokioMutex::new(PeerManager::new())),
@@ -106,15 +178,31 @@ impl Server {
                         let closer = close_notifier.clone();
 
                         let conn = conn.unwrap();
+                        let watched = conn.clone();
                         let manager = peer_manager.clone();
                         let mut manager = manager.lock().await;
                         let next_id = manager.get_next_id();
                         let peer = Arc::new(Peer::init(conn, closer, next_id).await);
 
-                        if let Err(_) = manager.add_peer(peer).await {
+                        let added = manager.add_peer(peer.clone()).await;
+                        drop(manager);
+
+                        if let Err(_) = added {
                             log_error!("Failed to add peer to manager.");
-                            // close the peer
+                            // close the peer, nothing else will as it is not managed.
+                            let _ = watched.close(DisconnectReason::Conflict).await;
+                            continue;
                         }
+
+                        // remove the peer once it's connection dies, IE: it stopped sending heartbeats.
+                        let dead_peers = peer_manager.clone();
+                        tokio::task::spawn(async move {
+                            watched.disconnected().await;
+                            log_debug!("Peer {} disconnected, removing.", watched.get_addr());
+                            dead_peers.lock().await.remove_peer(&peer);
+                            // closing can take a while, so it's done without holding the manager.
+                            let _ = watched.close(DisconnectReason::Closed).await;
+                        });
                     }
                 }
             }
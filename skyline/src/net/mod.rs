@@ -1,6 +1,13 @@
 // #[cfg(feature = "udp")]
 pub mod udp;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use crate::utils::to_address_token;
 
@@ -11,6 +18,8 @@ pub mod tcp;
 use async_trait::async_trait;
 use protocol::skyline::connection::DisconnectReason;
 
+pub use protocol::net::udp::queue::send::SendPriority;
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ListenerState {
     Ready,
@@ -28,6 +37,89 @@ pub enum ConnState {
     Disconnected,
 }
 
+/// How a packet is delivered to the peer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Reliability {
+    /// The packet is sent once, and is processed as soon as it arrives.
+    Unreliable,
+    /// The packet is sent once, and is only processed if it is the newest packet on it's ordering lane.
+    /// Useful for state updates, where an old update is worse than none.
+    UnreliableSequenced,
+    /// The packet is resent until it is acknowledged, and is processed as soon as it arrives.
+    Reliable,
+    /// The packet is resent until it is acknowledged, and is processed in the order it was sent on it's ordering lane.
+    ReliableOrdered,
+}
+
+/// Options for `ConnAdapter::send_with`.
+/// The default is a reliable, ordered packet with `Medium` priority, this is what `ConnAdapter::send` uses.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SendOptions {
+    pub reliability: Reliability,
+    /// The priority of a reliable packet, unreliable packets are always sent immediately.
+    pub priority: SendPriority,
+    /// The lane the packet is ordered or sequenced on, this must be below `protocol::MAX_ORDER_CHANNELS`.
+    /// If `None`, the lane of the packet's channel is used.
+    pub ordering_lane: Option<u16>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            reliability: Reliability::ReliableOrdered,
+            priority: SendPriority::Medium,
+            ordering_lane: None,
+        }
+    }
+}
+
+impl SendOptions {
+    pub fn new(reliability: Reliability) -> Self {
+        Self {
+            reliability,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_priority(mut self, priority: SendPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_ordering_lane(mut self, lane: u16) -> Self {
+        self.ordering_lane = Some(lane);
+        self
+    }
+}
+
+/// Returned by `ConnAdapter::send_confirmed`, this resolves once the peer has acknowledged the packet.
+/// It fails with `TimedOut` if the packet is not acknowledged in time, or `ConnectionAborted`
+/// if the packet was dropped or the connection closed first.
+pub struct DeliveryReceipt {
+    inner: Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>,
+}
+
+impl DeliveryReceipt {
+    pub fn new(future: impl Future<Output = std::io::Result<()>> + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(future),
+        }
+    }
+
+    /// A receipt that has already resolved.
+    pub fn delivered() -> Self {
+        Self::new(std::future::ready(Ok(())))
+    }
+}
+
+impl Future for DeliveryReceipt {
+    type Output = std::io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.inner.as_mut().poll(cx)
+    }
+}
+
 /// This is a very basic interface to send and recieve packets from a connection.
 /// Please note that this is not a full implementation of the protocol, and is
 /// only used to send and recieve packets.
@@ -41,6 +133,27 @@ pub trait ConnAdapter: Send + Sync {
     async fn close(&self, reason: DisconnectReason) -> std::io::Result<()>;
     /// Sends a skyline packet to the connection.
     async fn send(&self, packet: &protocol::skyline::SkylinePacket) -> std::io::Result<()>;
+    /// Sends a skyline packet to the connection with the given delivery options.
+    /// Transports that can't honor the options (IE: TCP) send the packet reliably and in order.
+    async fn send_with(
+        &self,
+        packet: &protocol::skyline::SkylinePacket,
+        _options: SendOptions,
+    ) -> std::io::Result<()> {
+        self.send(packet).await
+    }
+    /// Sends a skyline packet reliably, the returned receipt resolves once the peer has
+    /// acknowledged it, or fails if it isn't acknowledged within `timeout`.
+    /// Transports without acknowledgements (IE: TCP) resolve the receipt once the packet is written.
+    async fn send_confirmed(
+        &self,
+        packet: &protocol::skyline::SkylinePacket,
+        options: SendOptions,
+        _timeout: Duration,
+    ) -> std::io::Result<DeliveryReceipt> {
+        self.send_with(packet, options).await?;
+        Ok(DeliveryReceipt::delivered())
+    }
     /// Recieves a skyline packet from the connection.
     /// This function will block until a packet is recieved.
     async fn recv(&self) -> std::io::Result<protocol::skyline::SkylinePacket>;
@@ -54,6 +167,16 @@ pub trait ConnAdapter: Send + Sync {
     async fn send_raw(&self, data: &[u8]) -> std::io::Result<()>;
     /// Gets the current state of the connection.
     fn get_state(&self) -> ConnState;
+    /// What was negotiated during the TLS handshake, IE: the peer's certificate.
+    /// This is `None` if the connection does not use TLS.
+    fn get_tls_session(&self) -> Option<tcp::tls::TlsSession> {
+        None
+    }
+    /// Resolves once the connection is `Disconnected`, IE: the peer closed the connection or stopped responding.
+    /// Transports that can't tell never resolve.
+    async fn disconnected(&self) {
+        std::future::pending::<()>().await
+    }
     /// This will get the address of the connection.
     /// While not required, it is recommended to use this as the address of the connection.
     fn get_addr(&self) -> SocketAddr;
@@ -1 +1,123 @@
+use std::sync::Arc;
+use std::time::Duration;
 
+use protocol::net::tcp::{Connect, Messages, PROTOCOL_VERSION};
+use protocol::skyline::connection::DisconnectReason;
+use tokio_rustls::rustls::ClientConfig;
+
+use super::{conn::Conn, tls, TcpOptions};
+use crate::net::ConnAdapter;
+
+/// How long connecting to a server can take, including the TLS handshake and the `Connect`/`Hello` exchange.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to a Skyline server over TCP.
+///
+/// The connection is the same `Conn` the server uses, so framing, splits and heartbeats
+/// work the same way on both sides.
+///
+/// ```ignore
+/// let conn = TcpConnector::connect("127.0.0.1:24833").await?;
+/// conn.send(&packet).await?;
+/// ```
+pub struct TcpConnector {
+    options: TcpOptions,
+    /// The TLS config and the name the server's certificate must be valid for.
+    tls: Option<(Arc<ClientConfig>, String)>,
+    timeout: Duration,
+}
+
+impl TcpConnector {
+    pub fn new() -> Self {
+        Self {
+            options: TcpOptions::default(),
+            tls: None,
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+
+    /// Connects to the server at `addr` with the default options, without TLS.
+    pub async fn connect(addr: &str) -> std::io::Result<Arc<dyn ConnAdapter>> {
+        Self::new().dial(addr).await
+    }
+
+    /// Sets the options for the connection, the heartbeat interval is the interval we ask the server for.
+    pub fn with_options(mut self, options: TcpOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Connects with TLS, see `tls::client_config`.
+    /// `server_name` is the name the server's certificate must be valid for.
+    pub fn with_tls(mut self, config: Arc<ClientConfig>, server_name: &str) -> Self {
+        self.tls = Some((config, server_name.to_string()));
+        self
+    }
+
+    /// Sets how long connecting can take before it fails with `TimedOut`.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Connects to the server at `addr`, the connection is returned once the server has accepted it.
+    pub async fn dial(&self, addr: &str) -> std::io::Result<Arc<dyn ConnAdapter>> {
+        let deadline = tokio::time::Instant::now() + self.timeout;
+
+        let stream = tokio::time::timeout_at(deadline, tokio::net::TcpStream::connect(addr))
+            .await
+            .map_err(|_| timed_out())??;
+        let peer_addr = stream.peer_addr()?;
+
+        let conn = match self.tls {
+            Some((ref config, ref server_name)) => {
+                let (stream, session) = tokio::time::timeout_at(
+                    deadline,
+                    tls::connect(config.clone(), server_name, stream),
+                )
+                .await
+                .map_err(|_| timed_out())??;
+                Conn::from_client_stream(stream, peer_addr, self.options, Some(session))
+            }
+            None => Conn::from_client_stream(stream, peer_addr, self.options, None),
+        };
+
+        let connect = Connect {
+            version: PROTOCOL_VERSION,
+            max_size: 1024,
+            heartbeat_interval: self
+                .options
+                .heartbeat_interval
+                .as_secs()
+                .min(u16::MAX as u64) as u16,
+        };
+
+        // the server replies with a hello, or disconnects us if it doesn't support our version.
+        let connected = tokio::time::timeout_at(deadline, async {
+            conn.send_message(Messages::Connect(connect)).await?;
+            conn.connected().await
+        })
+        .await
+        .unwrap_or_else(|_| Err(timed_out()));
+
+        if let Err(e) = connected {
+            let _ = conn.close(DisconnectReason::Closed).await;
+            return Err(e);
+        }
+
+        Ok(Arc::new(conn))
+    }
+}
+
+fn timed_out() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "Connecting to the server timed out",
+    )
+}
+
+impl Default for TcpConnector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
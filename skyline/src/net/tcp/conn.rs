@@ -1,143 +1,448 @@
 use std::collections::{BTreeMap, HashMap};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+use std::time::{Duration, SystemTime};
 use std::{net::SocketAddr, sync::Arc};
 
 use async_recursion::async_recursion;
 use async_trait::async_trait;
 use binary_util::interfaces::{Reader, Writer};
-use binary_util::ByteReader;
+use protocol::net::tcp::codec::FrameCodec;
 use protocol::net::tcp::{Disconnect, HeartbeatAck, Hello, Messages, Payload, SplitPacket};
 use protocol::skyline::{connection::DisconnectReason, SkylinePacket};
 use protocol::util::current_epoch;
-use tokio::sync::{Mutex, Notify, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::{mpsc, watch, Mutex, Notify};
 
 use colored::*;
 
+use super::{tls::TlsSession, SlowReaderPolicy, TcpOptions};
 use crate::log_debug;
 use crate::net::{ConnAdapter, ConnState};
 
+/// The amount of splits that can be reassembled at the same time.
+const MAX_CONCURRENT_SPLITS: usize = 64;
+/// The amount of bytes that can be buffered by partial splits.
+const MAX_SPLIT_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+/// How long a split has to be completed before it is discarded.
+const SPLIT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// How long queued frames have to be written when the connection closes.
+const FLUSH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Splits we sent that the peer has not acknowledged yet, by split id.
+/// This is only locked while a message is split, never while waiting on the outbound queue.
+type SentSplits = std::sync::Mutex<HashMap<u16, (SystemTime, Vec<SplitPacket>)>>;
+
+/// Partial splits recieved from the peer.
+/// These are bounded, so a peer can not exhaust memory by never completing a split.
+#[derive(Default)]
+struct SplitReassembly {
+    parts: BTreeMap<u16, (SystemTime, BTreeMap<u16, SplitPacket>)>,
+    /// The amount of bytes buffered by all partial splits.
+    buffered: usize,
+}
+
+impl SplitReassembly {
+    /// Discards every split that has not been completed within `SPLIT_TIMEOUT`.
+    fn expire(&mut self) {
+        let expired = self
+            .parts
+            .iter()
+            .filter(|(_, (started, _))| {
+                started.elapsed().unwrap_or_default() >= SPLIT_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<u16>>();
+
+        for id in expired {
+            self.remove(id);
+        }
+    }
+
+    fn remove(&mut self, id: u16) -> Option<BTreeMap<u16, SplitPacket>> {
+        let (_, parts) = self.parts.remove(&id)?;
+        self.buffered = self
+            .buffered
+            .saturating_sub(parts.values().map(|p| p.data.len()).sum::<usize>());
+        Some(parts)
+    }
+
+    /// Inserts a part, returning the joined buffer if the split is complete.
+    /// An error is returned if the split is invalid or would exceed the limits.
+    fn insert(&mut self, split: SplitPacket) -> std::io::Result<Option<Vec<u8>>> {
+        if split.index >= split.size || split.size > protocol::MAX_SPLIT_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Split index is out of bounds",
+            ));
+        }
+
+        if self.buffered + split.data.len() > MAX_SPLIT_BUFFER_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "Split buffer limit exceeded",
+            ));
+        }
+
+        if !self.parts.contains_key(&split.id) {
+            if self.parts.len() >= MAX_CONCURRENT_SPLITS {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::OutOfMemory,
+                    "Too many concurrent splits",
+                ));
+            }
+
+            self.parts
+                .insert(split.id, (SystemTime::now(), BTreeMap::new()));
+        }
+
+        let (id, size) = (split.id, split.size);
+        let (_, parts) = self.parts.get_mut(&id).unwrap();
+
+        if !parts.contains_key(&split.index) {
+            self.buffered += split.data.len();
+            parts.insert(split.index, split);
+        }
+
+        if parts.len() as u16 != size {
+            return Ok(None);
+        }
+
+        let parts = self.remove(id).unwrap();
+        let mut buffer = Vec::new();
+
+        for (_, part) in parts {
+            buffer.extend(part.data);
+        }
+
+        Ok(Some(buffer))
+    }
+}
+
+/// The sending half of a connection's outbound queue.
+/// Frames are written to the socket in the order they are queued, by the writer task.
+#[derive(Clone)]
+struct Outbound {
+    tx: mpsc::Sender<Vec<u8>>,
+    policy: SlowReaderPolicy,
+    closer: Arc<Notify>,
+}
+
+impl Outbound {
+    /// Queues a frame to be written, waiting for room if the queue is full.
+    /// If the peer is too slow for the `SlowReaderPolicy`, the connection is closed.
+    async fn push(&self, frame: Vec<u8>) -> std::io::Result<()> {
+        let result = match self.policy {
+            SlowReaderPolicy::Wait => self.tx.send(frame).await.map_err(|_| ()),
+            SlowReaderPolicy::Disconnect(timeout) => {
+                match self.tx.send_timeout(frame, timeout).await {
+                    Ok(_) => Ok(()),
+                    Err(SendTimeoutError::Timeout(_)) => {
+                        self.closer.notify_waiters();
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "Peer is not reading, disconnecting",
+                        ));
+                    }
+                    Err(SendTimeoutError::Closed(_)) => Err(()),
+                }
+            }
+        };
+
+        result.map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Connection closed")
+        })
+    }
+
+    /// Queues a frame without waiting, this is used by the read task, which must never wait on the writer.
+    /// If the queue is full the frame is dropped, the peer is not reading what we send it anyway.
+    fn try_push(&self, frame: Vec<u8>) -> std::io::Result<()> {
+        self.tx.try_send(frame).map_err(|e| match e {
+            TrySendError::Full(_) => {
+                std::io::Error::new(std::io::ErrorKind::WouldBlock, "Outbound queue is full")
+            }
+            TrySendError::Closed(_) => {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Connection closed")
+            }
+        })
+    }
+}
+
+/// Tracks whether the peer is still alive, this is shared between the connection's tasks.
+struct Liveness {
+    state: watch::Sender<ConnState>,
+    /// Heartbeat intervals that passed without a heartbeat from the peer.
+    missed: AtomicU8,
+    /// The negotiated heartbeat interval, in seconds.
+    interval: AtomicU16,
+    /// Whether we connected to the peer, only clients expect a `Hello`.
+    client: bool,
+    /// Why the peer disconnected us, if it told us.
+    reason: std::sync::Mutex<Option<Disconnect>>,
+}
+
+impl Liveness {
+    fn new(interval: Duration, client: bool) -> Self {
+        Self {
+            state: watch::channel(ConnState::Connecting).0,
+            missed: AtomicU8::new(0),
+            interval: AtomicU16::new(interval.as_secs().clamp(1, u16::MAX as u64) as u16),
+            client,
+            reason: std::sync::Mutex::new(None),
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.load(Ordering::SeqCst) as u64)
+    }
+
+    /// Negotiates the heartbeat interval with the interval the peer requested in it's `Connect`.
+    /// The longer of the two is used, returning the negotiated interval in seconds.
+    fn connected(&self, requested: u16) -> u16 {
+        let interval = self.interval.load(Ordering::SeqCst).max(requested);
+        self.interval.store(interval, Ordering::SeqCst);
+        self.missed.store(0, Ordering::SeqCst);
+        self.state.send_replace(ConnState::Connected);
+        interval
+    }
+
+    /// The peer sent a heartbeat.
+    fn heartbeat(&self) {
+        self.missed.store(0, Ordering::SeqCst);
+        self.state.send_if_modified(|state| {
+            if *state == ConnState::TimingOut {
+                *state = ConnState::Connected;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Called once every heartbeat interval, returns false if the peer has missed
+    /// too many heartbeats and is now disconnected.
+    ///
+    /// The first missed heartbeat is forgiven, as the peer's heartbeats may just be late.
+    fn tick(&self, max_missed: u8) -> bool {
+        let missed = self.missed.fetch_add(1, Ordering::SeqCst).saturating_add(1);
+
+        if missed > max_missed {
+            self.disconnected();
+            return false;
+        }
+
+        if missed > 1 {
+            self.state.send_if_modified(|state| {
+                if *state == ConnState::Connected {
+                    *state = ConnState::TimingOut;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+
+        true
+    }
+
+    fn disconnected(&self) {
+        self.state.send_replace(ConnState::Disconnected);
+    }
+
+    /// The error for a connection that closed before the handshake completed.
+    fn refused(&self) -> std::io::Error {
+        match *self.reason.lock().unwrap() {
+            Some(Disconnect::InvalidProtocol) => std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!(
+                    "Server does not support protocol version {}",
+                    protocol::net::tcp::PROTOCOL_VERSION
+                ),
+            ),
+            Some(reason) => std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("Server refused connection: {}", reason),
+            ),
+            None => std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "Connection closed before the handshake completed",
+            ),
+        }
+    }
+}
+
 /// This struct represents a raw TCP connection and provides a base
 /// abstraction for the server to use via `ConnAdapter`.
 pub struct Conn {
     pub addr: SocketAddr,
-    pub state: ConnState,
+    liveness: Arc<Liveness>,
+    /// What was negotiated during the TLS handshake, if the connection uses TLS.
+    tls: Option<TlsSession>,
     close_notifier: Arc<Notify>,
     /// Single channel for digesting skyline packets.
     net_rx: Mutex<tokio::sync::mpsc::Receiver<SkylinePacket>>,
-    socket: Arc<tokio::net::TcpStream>,
+    /// Frames queued for the writer task.
+    outbound: Outbound,
     /// This is a queue of sent packets that have been split.
-    splits: Arc<RwLock<HashMap<u16, (SystemTime, Vec<SplitPacket>)>>>,
+    splits: Arc<SentSplits>,
     /// Tasks that are spawned by this connection.
     tasks: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
 }
 
 impl Conn {
-    pub fn new(stream: tokio::net::TcpStream) -> Self {
+    pub fn new(stream: tokio::net::TcpStream, options: TcpOptions) -> Self {
+        let addr = stream.peer_addr().unwrap();
+        Self::from_stream(stream, addr, options, None)
+    }
+
+    /// Creates a connection over any stream, IE: a TLS stream.
+    /// `tls` is the session negotiated by the stream, if any.
+    pub fn from_stream<S>(
+        stream: S,
+        addr: SocketAddr,
+        options: TcpOptions,
+        tls: Option<TlsSession>,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::init(stream, addr, options, tls, false)
+    }
+
+    /// Creates the client side of a connection, the caller sends the `Connect` and waits with `Conn::connected`.
+    pub(crate) fn from_client_stream<S>(
+        stream: S,
+        addr: SocketAddr,
+        options: TcpOptions,
+        tls: Option<TlsSession>,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Self::init(stream, addr, options, tls, true)
+    }
+
+    fn init<S>(
+        stream: S,
+        addr: SocketAddr,
+        options: TcpOptions,
+        tls: Option<TlsSession>,
+        client: bool,
+    ) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
         // initialize new notifier;
         let close_notifier = Arc::new(Notify::new());
-        let addr = stream.peer_addr().unwrap();
         let (pak_tx, pak_rx) = tokio::sync::mpsc::channel::<SkylinePacket>(100);
+        let (frame_tx, frame_rx) = mpsc::channel::<Vec<u8>>(options.send_queue_size.max(1));
+
+        let (mut reader, writer) = tokio::io::split(stream);
+
+        let outbound = Outbound {
+            tx: frame_tx,
+            policy: options.slow_reader,
+            closer: Arc::clone(&close_notifier),
+        };
+
+        // the writer is not aborted on close, so it can flush the disconnect.
+        tokio::spawn(Self::write_frames(
+            addr,
+            writer,
+            frame_rx,
+            Arc::clone(&close_notifier),
+        ));
 
-        let socket = Arc::new(stream);
-        let self_socket = Arc::clone(&socket);
+        let liveness = Arc::new(Liveness::new(options.heartbeat_interval, client));
 
-        let heartbeat_socket = Arc::clone(&socket);
+        let heartbeat_outbound = outbound.clone();
         let heartbeat_notifier = Arc::clone(&close_notifier);
+        let heartbeat_liveness = Arc::clone(&liveness);
         let heartbeat_task = tokio::task::spawn(async move {
             loop {
                 tokio::select! {
                     _ = heartbeat_notifier.notified() => {
                         break;
                     }
-                    _ = heartbeat_socket.writable() => {
-                        let heartbeat = Messages::HeartbeatAck(HeartbeatAck {
-                            timestamp: SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs(),
-                        });
-
-                        if let Err(_) =
-                            Self::send_packet(&heartbeat_socket, &mut HashMap::new(), heartbeat).await
-                        {
-                            println!("[{}] Error: Failed to send heartbeat packet...", addr);
-                        }
-                        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                    }
+                    _ = tokio::time::sleep(heartbeat_liveness.interval()) => {}
                 };
+
+                if !heartbeat_liveness.tick(options.max_missed_heartbeats) {
+                    println!(
+                        "[{}] Missed {} heartbeats, disconnecting",
+                        addr, options.max_missed_heartbeats
+                    );
+                    heartbeat_notifier.notify_waiters();
+                    break;
+                }
+
+                let heartbeat = Messages::HeartbeatAck(HeartbeatAck {
+                    timestamp: SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                });
+
+                if let Err(_) = heartbeat_outbound.push(Self::frame(heartbeat)).await {
+                    println!("[{}] Error: Failed to send heartbeat packet...", addr);
+                }
             }
         });
 
         let net_closer = Arc::clone(&close_notifier);
-        let recv_splits = Arc::new(RwLock::new(HashMap::new()));
+        let recv_splits = Arc::new(SentSplits::default());
 
         let recv_splits_net = Arc::clone(&recv_splits);
+        let recv_outbound = outbound.clone();
+        let recv_liveness = Arc::clone(&liveness);
 
         let recv_task = tokio::spawn(async move {
             // loop until closed or disconnect
             let mut buf: [u8; 1024] = [0; 1024];
-            // todo: this is hacky but works for now.
-            //       currently we use custom TCP proto (while small) not fully implemented.
-            let mut current = Vec::new();
-            let mut split_parts: BTreeMap<u16, BTreeMap<u16, SplitPacket>> = BTreeMap::new();
+            let mut codec = FrameCodec::new().with_max_frame_size(options.max_frame_size);
+            let mut split_parts = SplitReassembly::default();
 
             'outer: loop {
                 tokio::select! {
                     _ = net_closer.notified() => {
                         break;
                     }
-                    _ = socket.readable() => {
-                        // todo: Modify binary-util to allow for a stream to be passed in.
-                        //       this will make it easier to read packets from a stream.
-                        match socket.try_read(&mut buf) {
+                    read = reader.read(&mut buf) => {
+                        match read {
                             Ok(0) => {
                                 // No data was read...
                                 break;
                             }
                             Ok(n) => {
-                                // read n bytes
-                                current.extend(&buf[..n]);
-
-                                // check the current buffer for a frame.
-                                let mut reader = ByteReader::from(&current[..]);
-
-                                'pk_lp: loop {
-                                    if reader.as_slice().len() < 2 {
-                                        break 'pk_lp;
-                                    }
-
-                                    if current[1] != 54 {
-                                        println!("[{}] Invalid frame ID", addr);
-                                        current.clear();
-                                        break;
-                                    }
-
-                                    let mut recv_splits = recv_splits_net.write().await;
-
-                                    match reader.read_type::<protocol::net::tcp::Frame>() {
-                                        Ok(frame) => {
-                                            if let Err(e) = Self::process_tcp_message(
-                                                &socket,
-                                                &frame.message,
-                                                &mut split_parts,
-                                                &mut recv_splits,
-                                                &pak_tx
-                                            ).await {
-                                                println!("[{}] Error: {}", addr, e);
-                                                break 'outer;
-                                            }
-                                        }
-
+                                codec.extend(&buf[..n]);
+
+                                // a read may contain part of a frame, or several frames.
+                                loop {
+                                    let frame = match codec.decode() {
+                                        Ok(Some(frame)) => frame,
+                                        // wait for the rest of the frame.
+                                        Ok(None) => break,
                                         Err(e) => {
+                                            // the stream is out of sync, or the peer is misbehaving.
                                             println!("[{}] Error: {}", addr, e);
-                                            break 'pk_lp;
+                                            break 'outer;
                                         }
+                                    };
+
+                                    if let Err(e) = Self::process_tcp_message(
+                                        addr,
+                                        &recv_outbound,
+                                        &recv_liveness,
+                                        &frame.message,
+                                        &mut split_parts,
+                                        &recv_splits_net,
+                                        &pak_tx
+                                    ).await {
+                                        println!("[{}] Error: {}", addr, e);
+                                        break 'outer;
                                     }
                                 }
                             }
-                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                continue;
-                            }
                             Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
                                 println!("[{}] Client disconnected", addr);
                                 break;
@@ -155,6 +460,7 @@ impl Conn {
             }
 
             // notify the closer
+            recv_liveness.disconnected();
             net_closer.notify_waiters();
         });
 
@@ -164,28 +470,94 @@ impl Conn {
 
         Self {
             addr: addr,
-            state: ConnState::Connecting,
+            liveness,
+            tls,
             close_notifier,
             net_rx: Mutex::new(pak_rx),
-            socket: self_socket,
+            outbound,
             splits: recv_splits,
             tasks: Arc::new(Mutex::new(tasks)),
         }
     }
 
+    /// Writes queued frames to the socket until the connection is closed.
+    /// Each frame is written completely before the next one, so frames are never truncated or interleaved.
+    async fn write_frames<W: AsyncWrite + Unpin>(
+        addr: SocketAddr,
+        mut writer: W,
+        mut frames: mpsc::Receiver<Vec<u8>>,
+        closer: Arc<Notify>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = closer.notified() => {
+                    break;
+                }
+                frame = frames.recv() => {
+                    let frame = match frame {
+                        Some(frame) => frame,
+                        None => break,
+                    };
+
+                    tokio::select! {
+                        _ = closer.notified() => {
+                            // the frame was partially written, nothing after it can be read by the peer.
+                            return;
+                        }
+                        result = writer.write_all(&frame) => {
+                            if let Err(e) = result {
+                                println!("[{}] Error: {}", addr, e);
+                                closer.notify_waiters();
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // write whatever is still queued (IE: the disconnect), the peer may not be reading so this is bounded.
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, async {
+            while let Ok(frame) = frames.try_recv() {
+                writer.write_all(&frame).await?;
+            }
+            writer.shutdown().await
+        })
+        .await;
+    }
+
+    /// Waits for the server's `Hello`, failing if the connection closes first.
+    pub(crate) async fn connected(&self) -> std::io::Result<()> {
+        let mut state = self.liveness.state.subscribe();
+
+        loop {
+            match *state.borrow_and_update() {
+                ConnState::Connected | ConnState::TimingOut => return Ok(()),
+                ConnState::Disconnected => return Err(self.liveness.refused()),
+                _ => {}
+            }
+
+            if state.changed().await.is_err() {
+                return Err(self.liveness.refused());
+            }
+        }
+    }
+
     #[async_recursion]
     async fn process_tcp_message(
-        socket: &Arc<tokio::net::TcpStream>,
+        addr: SocketAddr,
+        outbound: &Outbound,
+        liveness: &Liveness,
         buf: &[u8],
-        recv_splits: &mut BTreeMap<u16, BTreeMap<u16, SplitPacket>>,
-        send_splits: &mut HashMap<u16, (SystemTime, Vec<SplitPacket>)>,
+        recv_splits: &mut SplitReassembly,
+        send_splits: &SentSplits,
         sender: &tokio::sync::mpsc::Sender<SkylinePacket>,
     ) -> std::io::Result<()> {
-        let addr = socket.peer_addr().unwrap();
         if let Ok(message) = Messages::read_from_slice(&buf) {
             match message {
                 Messages::Disconnect(reason) => {
                     println!("[{}] Client disconnected: {:?}", addr, reason);
+                    *liveness.reason.lock().unwrap() = Some(reason);
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::Other,
                         "Client disconnected",
@@ -196,12 +568,11 @@ impl Conn {
                     // send a hello packet.
                     if connect.version > protocol::net::tcp::PROTOCOL_VERSION {
                         println!("[{}] Error: Client protocol version is too new", addr);
-                        if let Err(_) = Self::send_disconnect(
-                            &socket,
+                        Self::send_disconnect(
+                            addr,
+                            outbound,
                             protocol::net::tcp::Disconnect::InvalidProtocol,
-                        )
-                        .await
-                        {}
+                        );
                         // recommend disconnect.
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::Other,
@@ -211,12 +582,11 @@ impl Conn {
 
                     if connect.version < protocol::net::tcp::PROTOCOL_VERSION {
                         println!("[{}] Error: Client protocol version is too old", addr);
-                        if let Err(_) = Self::send_disconnect(
-                            &socket,
+                        Self::send_disconnect(
+                            addr,
+                            outbound,
                             protocol::net::tcp::Disconnect::InvalidProtocol,
-                        )
-                        .await
-                        {}
+                        );
                         // recommend disconnect.
                         return Err(std::io::Error::new(
                             std::io::ErrorKind::Other,
@@ -226,65 +596,65 @@ impl Conn {
 
                     // send hello.
                     let hello = Hello {
-                        interval: 10_u16,
+                        interval: liveness.connected(connect.heartbeat_interval),
                         timestamp: Some(current_epoch()),
                     };
 
                     let hello = Messages::Hello(hello);
 
-                    if let Err(_) = Self::send_packet(socket, send_splits, hello).await {
-                        // dont error here...
+                    Self::reply(addr, outbound, hello);
+                }
+
+                Messages::Hello(hello) => {
+                    // only clients expect a hello, in reply to their connect.
+                    if liveness.client && *liveness.state.borrow() == ConnState::Connecting {
+                        let interval = liveness.connected(hello.interval);
+                        log_debug!("[{}] Connected, heartbeat interval: {}s", addr, interval);
                     }
                 }
 
                 Messages::HeartbeatAck(heartbeat) => {
-                    println!("[{}] Heartbeat: {}", addr, heartbeat.timestamp);
+                    log_debug!("[{}] Heartbeat: {}", addr, heartbeat.timestamp);
+                    liveness.heartbeat();
                 }
 
                 Messages::SplitOk(split_ok) => {
-                    send_splits.remove(&split_ok.id);
+                    send_splits.lock().unwrap().remove(&split_ok.id);
                 }
 
                 Messages::SplitPacket(split) => {
-                    if !recv_splits.contains_key(&split.id) {
-                        recv_splits.insert(split.id, BTreeMap::new());
-                    }
-
-                    let parts = recv_splits.get_mut(&split.id).unwrap();
-
-                    if !parts.contains_key(&split.index) {
-                        parts.insert(split.index, split.clone());
-                    }
-
-                    if parts.len() as u16 == split.size {
-                        let mut buffer = Vec::new();
-
-                        for i in 0..split.size {
-                            let part = parts.get(&i).unwrap();
-                            buffer.extend(&part.data);
+                    recv_splits.expire();
+
+                    let id = split.id;
+                    let buffer = match recv_splits.insert(split) {
+                        Ok(Some(buffer)) => buffer,
+                        Ok(None) => return Ok(()),
+                        Err(e) => {
+                            println!("[{}] Error: {}", addr, e);
+                            Self::send_disconnect(
+                                addr,
+                                outbound,
+                                protocol::net::tcp::Disconnect::LimitExceeded,
+                            );
+                            // the peer is misbehaving, disconnect.
+                            return Err(e);
                         }
+                    };
 
-                        let split_ok =
-                            Messages::SplitOk(protocol::net::tcp::SplitOk { id: split.id });
-
-                        if let Err(_) = Self::send_packet(socket, send_splits, split_ok).await {
-                            // dont error here...
-                            log_debug!("Failed to send split ok packet to: {:?}", addr);
-                        }
+                    let split_ok = Messages::SplitOk(protocol::net::tcp::SplitOk { id });
 
-                        recv_splits.remove(&split.id);
+                    Self::reply(addr, outbound, split_ok);
 
-                        return Self::process_tcp_message(
-                            socket,
-                            &buffer,
-                            recv_splits,
-                            send_splits,
-                            sender,
-                        )
-                        .await;
-                    } else {
-                        return Ok(());
-                    }
+                    return Self::process_tcp_message(
+                        addr,
+                        outbound,
+                        liveness,
+                        &buffer,
+                        recv_splits,
+                        send_splits,
+                        sender,
+                    )
+                    .await;
                 }
                 Messages::Payload(payload) => {
                     if let Ok(packet) = SkylinePacket::read_from_slice(&payload.data) {
@@ -306,147 +676,90 @@ impl Conn {
         return Ok(());
     }
 
-    async fn send_disconnect(
-        socket: &Arc<tokio::net::TcpStream>,
-        reason: Disconnect,
-    ) -> std::io::Result<()> {
-        let disconnect = Messages::Disconnect(reason);
+    fn send_disconnect(addr: SocketAddr, outbound: &Outbound, reason: Disconnect) {
+        Self::reply(addr, outbound, Messages::Disconnect(reason));
+    }
 
-        if let Err(_) = Self::send_packet(socket, &mut HashMap::new(), disconnect).await {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Send Error"));
-        } else {
-            return Ok(());
+    /// Queues a reply from the read task, without waiting for room in the outbound queue.
+    /// Replies are small, so they are never split.
+    fn reply(addr: SocketAddr, outbound: &Outbound, message: Messages) {
+        if let Err(e) = outbound.try_push(Self::frame(message)) {
+            log_debug!("[{}] Failed to send reply: {}", addr, e);
         }
     }
 
+    /// Encodes a message as a single frame, without splitting it.
+    fn frame(message: Messages) -> Vec<u8> {
+        let buf = message.write_to_bytes().unwrap();
+        protocol::net::tcp::Frame::new(buf.as_slice().to_vec())
+            .write_to_bytes()
+            .unwrap()
+            .as_slice()
+            .to_vec()
+    }
+
     async fn send_packet(
-        socket: &Arc<tokio::net::TcpStream>,
-        splits: &mut HashMap<u16, (SystemTime, Vec<SplitPacket>)>,
+        outbound: &Outbound,
+        splits: &SentSplits,
         packet: Messages,
     ) -> std::io::Result<()> {
         let packet = packet.write_to_bytes().unwrap();
-        let buf = packet.as_slice();
-
-        if buf.len() >= (1024 - 60 - 12 - 100) {
-            // remove old splits
-            for (id, (time, _)) in splits.clone() {
-                if time.elapsed().unwrap().as_secs() > 10 {
-                    splits.remove(&id);
-                }
-            }
-
-            // get next available id
-            let next = splits.len() + 1;
-            let split_pks = SplitPacket::split(next as u16, buf).unwrap();
-            splits.insert(next as u16, (SystemTime::now(), split_pks.clone()));
-
-            for split_pk in split_pks {
-                let x = Messages::SplitPacket(split_pk).write_to_bytes().unwrap();
-                let bin = x.as_slice();
-
-                if let Err(_) = socket.writable().await {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Write Error",
-                    ));
-                }
-
-                let frame = protocol::net::tcp::Frame::new(bin.to_vec())
-                    .write_to_bytes()
-                    .unwrap();
-                if let Err(_) = socket.try_write(&frame.as_slice()) {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Write Error",
-                    ));
-                }
-            }
-
-            return Ok(());
-        } else {
-            let frame = protocol::net::tcp::Frame::new(buf.to_vec())
-                .write_to_bytes()
-                .unwrap();
-            if let Err(_) = socket.try_write(&frame.as_slice()) {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Write Error",
-                ));
-            } else {
-                return Ok(());
-            }
-        }
+        Self::send_raw(outbound, splits, packet.as_slice()).await
     }
 
-    #[allow(dead_code)]
-    async fn send_raw(
-        socket: &Arc<tokio::net::TcpStream>,
-        splits: &mut HashMap<u16, (SystemTime, Vec<SplitPacket>)>,
-        buf: &[u8],
-    ) -> std::io::Result<()> {
-        if buf.len() >= (1024 - 60 - 12 - 100) {
+    /// Queues a message, splitting it if it is too large.
+    /// This waits for room in the outbound queue, so a slow peer slows down the sender instead of losing data.
+    /// `splits` is only locked while the message is split, not while waiting for room.
+    async fn send_raw(outbound: &Outbound, splits: &SentSplits, buf: &[u8]) -> std::io::Result<()> {
+        let frames = if buf.len() >= (1024 - 60 - 12 - 100) {
+            let mut splits = splits.lock().unwrap();
+
             // remove old splits
-            for (id, (time, _)) in splits.clone() {
-                if time.elapsed().unwrap().as_secs() > 10 {
-                    splits.remove(&id);
-                }
-            }
+            splits.retain(|_, (time, _)| time.elapsed().unwrap_or_default().as_secs() <= 10);
 
             // get next available id
             let next = splits.len() + 1;
             let split_pks = SplitPacket::split(next as u16, buf).unwrap();
             splits.insert(next as u16, (SystemTime::now(), split_pks.clone()));
 
-            for split_pk in split_pks {
-                let x = Messages::SplitPacket(split_pk).write_to_bytes().unwrap();
-                let bin = x.as_slice();
-
-                if let Err(_) = socket.writable().await {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Write Error",
-                    ));
-                }
-
-                let frame = protocol::net::tcp::Frame::new(bin.to_vec())
-                    .write_to_bytes()
-                    .unwrap();
-                if let Err(_) = socket.try_write(&frame.as_slice()) {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Write Error",
-                    ));
-                }
-            }
-
-            return Ok(());
+            split_pks
+                .into_iter()
+                .map(|split_pk| Self::frame(Messages::SplitPacket(split_pk)))
+                .collect::<Vec<Vec<u8>>>()
         } else {
-            let frame = protocol::net::tcp::Frame::new(buf.to_vec())
+            vec![protocol::net::tcp::Frame::new(buf.to_vec())
                 .write_to_bytes()
-                .unwrap();
-            if let Err(_) = socket.try_write(&frame.as_slice()) {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Write Error",
-                ));
-            } else {
-                return Ok(());
-            }
+                .unwrap()
+                .as_slice()
+                .to_vec()]
+        };
+
+        for frame in frames {
+            outbound.push(frame).await?;
         }
+
+        Ok(())
     }
 }
 
 #[async_trait]
 impl ConnAdapter for Conn {
     async fn close(&self, reason: DisconnectReason) -> std::io::Result<()> {
-        self.close_notifier.notify_waiters();
         let disconnect = protocol::skyline::connection::Disconnect { reason };
 
-        self.send(&SkylinePacket::Disconnect(disconnect)).await?;
-        self.send_message(Messages::Disconnect(
-            protocol::net::tcp::Disconnect::SelfInitiated,
-        ))
-        .await?;
+        // queue the disconnect before closing, the writer flushes it on the way out.
+        // the peer may not be reading, so don't wait on it forever.
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, async {
+            self.send(&SkylinePacket::Disconnect(disconnect)).await?;
+            self.send_message(Messages::Disconnect(
+                protocol::net::tcp::Disconnect::SelfInitiated,
+            ))
+            .await
+        })
+        .await;
+
+        self.liveness.disconnected();
+        self.close_notifier.notify_waiters();
 
         let mut tasks = self.tasks.lock().await;
 
@@ -462,49 +775,21 @@ impl ConnAdapter for Conn {
 
         let tcp_pk = Messages::Payload(Payload { data: x });
 
-        let mut splits = self.splits.write().await;
-
-        if let Err(_) = Self::send_packet(&self.socket, &mut splits, tcp_pk).await {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Write Error",
-            ));
-        }
-
-        return Ok(());
+        Self::send_packet(&self.outbound, &self.splits, tcp_pk).await
     }
 
     async fn send_message(&self, message: protocol::net::tcp::Messages) -> std::io::Result<()> {
         // this will internally attempt to send the tcp packet.
-        let mut splits = self.splits.write().await;
-
-        if let Err(_) = Self::send_packet(&self.socket, &mut splits, message).await {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Write Error",
-            ));
-        }
-
-        return Ok(());
+        Self::send_packet(&self.outbound, &self.splits, message).await
     }
 
     async fn send_raw(&self, buf: &[u8]) -> std::io::Result<()> {
-        let mut splits = self.splits.write().await;
-
-        if let Err(_) = Self::send_packet(
-            &self.socket,
-            &mut splits,
+        Self::send_packet(
+            &self.outbound,
+            &self.splits,
             Messages::Payload(Payload { data: buf.to_vec() }),
         )
         .await
-        {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Write Error",
-            ));
-        }
-
-        return Ok(());
     }
 
     async fn recv(&self) -> Result<protocol::skyline::SkylinePacket, std::io::Error> {
@@ -522,7 +807,21 @@ impl ConnAdapter for Conn {
     }
 
     fn get_state(&self) -> ConnState {
-        self.state
+        *self.liveness.state.borrow()
+    }
+
+    fn get_tls_session(&self) -> Option<TlsSession> {
+        self.tls.clone()
+    }
+
+    async fn disconnected(&self) {
+        let mut state = self.liveness.state.subscribe();
+
+        while *state.borrow_and_update() != ConnState::Disconnected {
+            if state.changed().await.is_err() {
+                return;
+            }
+        }
     }
     fn get_addr(&self) -> SocketAddr {
         self.addr
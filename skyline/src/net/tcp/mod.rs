@@ -1,35 +1,143 @@
 /// A Client is the primary way to interact with a server.
 pub mod client;
 pub mod conn;
+/// Optional TLS for TCP connections.
+pub mod tls;
 
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::sync::Notify;
+use protocol::net::tcp::codec::DEFAULT_MAX_FRAME_SIZE;
+use tokio::sync::{mpsc, Notify};
+use tokio_rustls::rustls::ServerConfig;
+
+use colored::*;
 
 use super::{ListenerState, NetworkInterface};
+use crate::log_debug;
+
+/// The amount of connections that can finish their TLS handshake before one is accepted.
+const HANDSHAKE_QUEUE_SIZE: usize = 64;
+
+/// A connection that completed it's TLS handshake, waiting to be accepted.
+type Handshaked = (
+    tokio_rustls::server::TlsStream<tokio::net::TcpStream>,
+    SocketAddr,
+    tls::TlsSession,
+);
+
+/// What happens when a peer reads slower than we send, and it's outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowReaderPolicy {
+    /// Sends wait until there is room in the queue, however long that takes.
+    Wait,
+    /// Sends wait until there is room in the queue, if there is still no room after
+    /// the given duration the peer is disconnected.
+    Disconnect(Duration),
+}
+
+/// Options applied to every connection accepted by a `TcpListener`.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpOptions {
+    /// The largest frame a peer can send, in bytes.
+    /// A peer that sends a larger frame is disconnected.
+    pub max_frame_size: usize,
+    /// The amount of frames that can be queued for a peer before sends wait.
+    pub send_queue_size: usize,
+    /// What to do with a peer that isn't reading it's frames.
+    pub slow_reader: SlowReaderPolicy,
+    /// How often heartbeats are sent, a peer may negotiate a longer interval in it's `Connect`.
+    pub heartbeat_interval: Duration,
+    /// The amount of heartbeats a peer can miss in a row before it is disconnected.
+    pub max_missed_heartbeats: u8,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            send_queue_size: 256,
+            slow_reader: SlowReaderPolicy::Disconnect(Duration::from_secs(5)),
+            heartbeat_interval: Duration::from_secs(10),
+            max_missed_heartbeats: 3,
+        }
+    }
+}
 
 pub struct TcpListener {
     state: ListenerState,
     listener: tokio::net::TcpListener,
     notifier: Arc<Notify>,
+    options: TcpOptions,
+    /// If set, every connection must complete a TLS handshake before it is accepted.
+    tls: Option<Arc<ServerConfig>>,
+    /// Handshakes run in their own tasks, so a slow client doesn't hold up `accept`.
+    /// Connections that complete their handshake are sent here.
+    handshaked: (
+        mpsc::Sender<Handshaked>,
+        tokio::sync::Mutex<mpsc::Receiver<Handshaked>>,
+    ),
 }
 
 impl TcpListener {
     pub async fn init(addr: &str) -> std::io::Result<Self> {
         let listener = tokio::net::TcpListener::bind(addr).await?;
+        let (handshaked_tx, handshaked_rx) = mpsc::channel(HANDSHAKE_QUEUE_SIZE);
         Ok(Self {
             state: ListenerState::Ready,
             listener,
             notifier: Arc::new(Notify::new()),
+            options: TcpOptions::default(),
+            tls: None,
+            handshaked: (handshaked_tx, tokio::sync::Mutex::new(handshaked_rx)),
         })
     }
 
+    /// Sets the options applied to every connection accepted by this listener.
+    pub fn with_options(mut self, options: TcpOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Requires every connection to use TLS, see `tls::server_config`.
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
     async fn internal_accept(&self) -> std::io::Result<tokio::net::TcpStream> {
         let (stream, _) = self.listener.accept().await?;
         Ok(stream)
     }
 
+    /// Runs the TLS handshake for `stream` in it's own task.
+    /// Once it completes, the connection is handed to `accept`, a failed handshake is dropped.
+    fn handshake(&self, config: Arc<ServerConfig>, stream: tokio::net::TcpStream) {
+        let handshaked = self.handshaked.0.clone();
+        let notifier = self.notifier.clone();
+
+        tokio::spawn(async move {
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addr,
+                Err(_) => return,
+            };
+
+            tokio::select! {
+                _ = notifier.notified() => {}
+                result = tls::accept(config, stream) => match result {
+                    Ok((stream, session)) => {
+                        let _ = handshaked.send((stream, addr, session)).await;
+                    }
+                    Err(e) => {
+                        log_debug!("[{}] TLS handshake failed: {}", addr, e);
+                    }
+                }
+            }
+        });
+    }
+
     pub fn state(&self) -> ListenerState {
         self.state
     }
@@ -53,9 +161,28 @@ impl NetworkInterface for TcpListener {
     }
 
     async fn accept(&self) -> std::io::Result<Arc<dyn super::ConnAdapter>> {
-        let stream = self.internal_accept().await?;
-        let conn = conn::Conn::new(stream);
-        Ok(Arc::new(conn))
+        let config = match self.tls {
+            Some(ref config) => config,
+            None => {
+                let stream = self.internal_accept().await?;
+                return Ok(Arc::new(conn::Conn::new(stream, self.options)));
+            }
+        };
+
+        // keep accepting streams until one of the handshakes completes.
+        let mut handshaked = self.handshaked.1.lock().await;
+
+        loop {
+            tokio::select! {
+                stream = self.internal_accept() => {
+                    self.handshake(config.clone(), stream?);
+                }
+                Some((stream, addr, session)) = handshaked.recv() => {
+                    let conn = conn::Conn::from_stream(stream, addr, self.options, Some(session));
+                    return Ok(Arc::new(conn));
+                }
+            }
+        }
     }
 
     async fn close(&mut self) -> std::io::Result<()> {
@@ -0,0 +1,443 @@
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    RootCertStore,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// How long a peer has to complete the TLS handshake.
+pub const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the server treats client certificates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    /// Clients are not asked for a certificate.
+    None,
+    /// Clients may present a certificate, if they do it must be signed by the client CA.
+    Optional,
+    /// Clients must present a certificate signed by the client CA.
+    Required,
+}
+
+/// What was negotiated during the TLS handshake, exposed with `ConnAdapter::get_tls_session`.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSession {
+    /// The server name (SNI) the client connected to.
+    pub server_name: Option<String>,
+    /// The certificate chain the peer presented, DER encoded, starting with the peer's own certificate.
+    /// This is empty if the peer did not present a certificate.
+    pub peer_certificates: Vec<Vec<u8>>,
+    /// The negotiated ALPN protocol, if any.
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+impl TlsSession {
+    /// The peer's own certificate, DER encoded. This is the peer's verified identity.
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        self.peer_certificates.first().map(|cert| cert.as_slice())
+    }
+
+    fn new(
+        server_name: Option<&str>,
+        peer_certificates: Option<&[CertificateDer<'static>]>,
+        alpn_protocol: Option<&[u8]>,
+    ) -> Self {
+        Self {
+            server_name: server_name.map(|name| name.to_string()),
+            peer_certificates: peer_certificates
+                .unwrap_or_default()
+                .iter()
+                .map(|cert| cert.as_ref().to_vec())
+                .collect(),
+            alpn_protocol: alpn_protocol.map(|alpn| alpn.to_vec()),
+        }
+    }
+}
+
+fn invalid_data(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Reads every certificate from a PEM file.
+pub fn load_certificates(path: impl AsRef<Path>) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(std::fs::File::open(path.as_ref())?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
+
+    if certs.is_empty() {
+        return Err(invalid_data(format!(
+            "No certificates found in {}",
+            path.as_ref().display()
+        )));
+    }
+
+    Ok(certs)
+}
+
+/// Reads the first private key from a PEM file.
+pub fn load_private_key(path: impl AsRef<Path>) -> std::io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(std::fs::File::open(path.as_ref())?);
+
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+        invalid_data(format!(
+            "No private key found in {}",
+            path.as_ref().display()
+        ))
+    })
+}
+
+fn load_roots(path: impl AsRef<Path>) -> std::io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    for cert in load_certificates(path)? {
+        roots.add(cert).map_err(invalid_data)?;
+    }
+
+    Ok(roots)
+}
+
+/// Builds the TLS config for a `TcpListener` from PEM files.
+///
+/// `client_ca` is the CA client certificates are verified against, it is required unless `client_auth` is `None`.
+pub fn server_config(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+    client_ca: Option<&Path>,
+    client_auth: ClientAuth,
+) -> std::io::Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certificates(cert_path)?;
+    let key = load_private_key(key_path)?;
+
+    let builder = match (client_auth, client_ca) {
+        (ClientAuth::None, _) => rustls::ServerConfig::builder().with_no_client_auth(),
+        (_, None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "A client CA is required to verify client certificates",
+            ));
+        }
+        (auth, Some(ca)) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(ca)?));
+            let verifier = if auth == ClientAuth::Optional {
+                verifier.allow_unauthenticated().build()
+            } else {
+                verifier.build()
+            };
+
+            rustls::ServerConfig::builder()
+                .with_client_cert_verifier(verifier.map_err(invalid_data)?)
+        }
+    };
+
+    let config = builder.with_single_cert(certs, key).map_err(invalid_data)?;
+    Ok(Arc::new(config))
+}
+
+/// Builds the TLS config for connecting to a server.
+///
+/// The server's certificate is verified against `ca_path`. If `identity` is given, it is the
+/// certificate and private key paths presented to servers that verify client certificates.
+pub fn client_config(
+    ca_path: impl AsRef<Path>,
+    identity: Option<(&Path, &Path)>,
+) -> std::io::Result<Arc<rustls::ClientConfig>> {
+    let builder = rustls::ClientConfig::builder().with_root_certificates(load_roots(ca_path)?);
+
+    let config = match identity {
+        Some((cert_path, key_path)) => builder
+            .with_client_auth_cert(load_certificates(cert_path)?, load_private_key(key_path)?)
+            .map_err(invalid_data)?,
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Completes the server side of the TLS handshake.
+pub async fn accept<S>(
+    config: Arc<rustls::ServerConfig>,
+    stream: S,
+) -> std::io::Result<(tokio_rustls::server::TlsStream<S>, TlsSession)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = tokio::time::timeout(
+        TLS_HANDSHAKE_TIMEOUT,
+        TlsAcceptor::from(config).accept(stream),
+    )
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+
+    let (_, session) = stream.get_ref();
+    let session = TlsSession::new(
+        session.server_name(),
+        session.peer_certificates(),
+        session.alpn_protocol(),
+    );
+
+    Ok((stream, session))
+}
+
+/// Completes the client side of the TLS handshake, verifying the server is `server_name`.
+pub async fn connect<S>(
+    config: Arc<rustls::ClientConfig>,
+    server_name: &str,
+    stream: S,
+) -> std::io::Result<(tokio_rustls::client::TlsStream<S>, TlsSession)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+
+    let stream = tokio::time::timeout(
+        TLS_HANDSHAKE_TIMEOUT,
+        TlsConnector::from(config).connect(name, stream),
+    )
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))??;
+
+    let (_, session) = stream.get_ref();
+    let session = TlsSession::new(
+        Some(server_name),
+        session.peer_certificates(),
+        session.alpn_protocol(),
+    );
+
+    Ok((stream, session))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+
+    use super::*;
+
+    /// A CA that issues the certificates for a test.
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+        path: PathBuf,
+    }
+
+    /// A certificate and it's private key, written to PEM files.
+    struct Identity {
+        cert: PathBuf,
+        key: PathBuf,
+        der: Vec<u8>,
+    }
+
+    /// Writes `pem` to a file that is unique to this test run.
+    fn write_pem(pem: String) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "skyline-tls-{}-{}.pem",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&path, pem).unwrap();
+        path
+    }
+
+    fn new_ca() -> Ca {
+        let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let key = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        let path = write_pem(cert.pem());
+
+        Ca { cert, key, path }
+    }
+
+    fn issue(ca: &Ca, name: &str) -> Identity {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca.cert, &ca.key)
+            .unwrap();
+
+        Identity {
+            cert: write_pem(cert.pem()),
+            key: write_pem(key.serialize_pem()),
+            der: cert.der().to_vec(),
+        }
+    }
+
+    /// Runs both sides of a handshake over an in-memory stream.
+    async fn handshake(
+        server: Arc<rustls::ServerConfig>,
+        client: Arc<rustls::ClientConfig>,
+    ) -> (std::io::Result<TlsSession>, std::io::Result<TlsSession>) {
+        let (server_stream, client_stream) = tokio::io::duplex(64 * 1024);
+
+        let (server, client) = tokio::join!(
+            accept(server, server_stream),
+            connect(client, "localhost", client_stream)
+        );
+
+        (
+            server.map(|(_, session)| session),
+            client.map(|(_, session)| session),
+        )
+    }
+
+    #[tokio::test]
+    async fn plain_tls() {
+        let ca = new_ca();
+        let server = issue(&ca, "localhost");
+
+        let (server_session, client_session) = handshake(
+            server_config(&server.cert, &server.key, None, ClientAuth::None).unwrap(),
+            client_config(&ca.path, None).unwrap(),
+        )
+        .await;
+
+        let server_session = server_session.unwrap();
+        let client_session = client_session.unwrap();
+
+        assert_eq!(server_session.server_name.as_deref(), Some("localhost"));
+        assert_eq!(server_session.peer_certificate(), None);
+        assert_eq!(
+            client_session.peer_certificate(),
+            Some(server.der.as_slice())
+        );
+    }
+
+    #[tokio::test]
+    async fn server_name_mismatch() {
+        let ca = new_ca();
+        let server = issue(&ca, "example.com");
+
+        let (_, client_session) = handshake(
+            server_config(&server.cert, &server.key, None, ClientAuth::None).unwrap(),
+            client_config(&ca.path, None).unwrap(),
+        )
+        .await;
+
+        assert!(client_session.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_accepted() {
+        let ca = new_ca();
+        let server = issue(&ca, "localhost");
+        let client = issue(&ca, "client");
+
+        let (server_session, client_session) = handshake(
+            server_config(
+                &server.cert,
+                &server.key,
+                Some(ca.path.as_path()),
+                ClientAuth::Required,
+            )
+            .unwrap(),
+            client_config(
+                &ca.path,
+                Some((client.cert.as_path(), client.key.as_path())),
+            )
+            .unwrap(),
+        )
+        .await;
+
+        // the server's session identifies the client by it's certificate.
+        let server_session = server_session.unwrap();
+        assert_eq!(
+            server_session.peer_certificate(),
+            Some(client.der.as_slice())
+        );
+        assert_eq!(server_session.peer_certificates.len(), 1);
+
+        let client_session = client_session.unwrap();
+        assert_eq!(
+            client_session.peer_certificate(),
+            Some(server.der.as_slice())
+        );
+    }
+
+    #[tokio::test]
+    async fn client_certificate_rejected() {
+        let ca = new_ca();
+        let other_ca = new_ca();
+        let server = issue(&ca, "localhost");
+        let untrusted = issue(&other_ca, "client");
+        let config = server_config(
+            &server.cert,
+            &server.key,
+            Some(ca.path.as_path()),
+            ClientAuth::Required,
+        )
+        .unwrap();
+
+        // no certificate at all.
+        let (server_session, _) =
+            handshake(config.clone(), client_config(&ca.path, None).unwrap()).await;
+        assert!(server_session.is_err());
+
+        // a certificate from a CA the server doesn't trust.
+        let (server_session, _) = handshake(
+            config,
+            client_config(
+                &ca.path,
+                Some((untrusted.cert.as_path(), untrusted.key.as_path())),
+            )
+            .unwrap(),
+        )
+        .await;
+        assert!(server_session.is_err());
+    }
+
+    #[tokio::test]
+    async fn client_certificate_optional() {
+        let ca = new_ca();
+        let server = issue(&ca, "localhost");
+        let client = issue(&ca, "client");
+        let config = server_config(
+            &server.cert,
+            &server.key,
+            Some(ca.path.as_path()),
+            ClientAuth::Optional,
+        )
+        .unwrap();
+
+        let (server_session, _) =
+            handshake(config.clone(), client_config(&ca.path, None).unwrap()).await;
+        assert_eq!(server_session.unwrap().peer_certificate(), None);
+
+        let (server_session, _) = handshake(
+            config,
+            client_config(
+                &ca.path,
+                Some((client.cert.as_path(), client.key.as_path())),
+            )
+            .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            server_session.unwrap().peer_certificate(),
+            Some(client.der.as_slice())
+        );
+    }
+
+    #[test]
+    fn client_auth_requires_a_ca() {
+        let ca = new_ca();
+        let server = issue(&ca, "localhost");
+
+        let config = server_config(&server.cert, &server.key, None, ClientAuth::Required);
+        assert_eq!(
+            config.err().map(|e| e.kind()),
+            Some(std::io::ErrorKind::InvalidInput)
+        );
+    }
+}
@@ -1,217 +1,537 @@
 use std::{
-    net::{SocketAddr, UdpSocket},
-    sync::{atomic::AtomicU64, Arc},
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
 };
 
-use binary_util::{
-    interfaces::{Reader, Writer},
-    ByteReader,
-};
+use async_trait::async_trait;
+use binary_util::interfaces::{Reader, Writer};
 use protocol::{
-    net::udp::proto::{
-        online::{
-            ack::{AckVariant, Acknowledgeable, Acknowledgement},
-            OnlinePackets,
-        },
-        Packets,
-    },
-    net::udp::queue::{
-        recv::RecvQueue,
-        send::{SendPriority, SendQueue},
-    },
+    net::udp::connection::{Connection, ConnectionState, Event},
+    net::udp::proto::offline::KeyShare,
+    net::udp::queue::send::{order_channel, Receipt, SendOrdering, SendPriority, SendQueueStats},
+    net::udp::transport::DatagramTransport,
+    skyline::{connection::DisconnectReason, SkylinePacket},
 };
 use tokio::{
     sync::{
         mpsc::{Receiver, Sender},
-        Mutex, Notify, RwLock,
+        oneshot, watch, Mutex, Notify,
     },
     task::JoinHandle,
 };
 
-use crate::net::ConnState;
+use colored::*;
+
+use crate::log_debug;
+use crate::net::{ConnAdapter, ConnState, DeliveryReceipt, Reliability, SendOptions};
 
 pub(crate) type ConnNetChan = Arc<Mutex<Receiver<Vec<u8>>>>;
+/// Pending delivery receipts by id, each is resolved with whether the packet was delivered.
+type ReceiptMap = Arc<StdMutex<HashMap<u32, oneshot::Sender<bool>>>>;
 
-#[derive(Debug, PartialEq)]
-#[repr(u8)]
-pub enum ProcessingStatus {
-    Disconnect,
-    NotAllowed,
-    Ok,
+/// Sent by a connection to the listener, so it can keep it's connection map up to date.
+/// Connections are identified by their connection id, as their address can change.
+/// These are sent over a single channel so they are handled in order.
+pub(crate) enum ConnEvent {
+    /// The connection is closed, and should be removed.
+    Closed(u64),
+    /// The peer's address changed, IE: a NAT rebinding.
+    Migrated { connection_id: u64, to: SocketAddr },
 }
 
-/// Primary structure for handling concurrent connections with
-/// the skyline protocol.
+/// The amount of datagrams that can be buffered for a connection before
+/// the listener starts dropping them.
+const DATAGRAM_BUFFER_SIZE: usize = 512;
+
+/// Drives a `Connection` with tokio.
 ///
-/// This does not implement the advanced features of the protocol,
-/// but is rather a barebones implementation of the protocol.
+/// The protocol itself is implemented by `protocol::net::udp::connection::Connection`,
+/// this only moves datagrams between it and the socket, and wakes it when it's timers expire.
 pub struct Conn {
-    addr: SocketAddr,
-    state: Mutex<ConnState>,
-    socket: Arc<tokio::net::UdpSocket>,
+    /// The peer's address, this changes if the connection migrates.
+    addr: Arc<StdMutex<SocketAddr>>,
+    /// The id the connection is known by, if it's address changes.
+    connection_id: u64,
+    mtu: u16,
+    /// Our public key from the handshake.
+    key_share: KeyShare,
+    connection: Arc<StdMutex<Connection>>,
+    socket: Arc<dyn DatagramTransport>,
+    /// Raw datagrams for this connection and where they came from, these are dispatched by the listener.
+    datagram_tx: Sender<(SocketAddr, Vec<u8>)>,
     /// The api for Conn::recv()
     network_recv: ConnNetChan,
     disconnect: Arc<Notify>,
+    /// Set once the driver has stopped, IE: the connection was closed or timed out.
+    closed: Arc<watch::Sender<bool>>,
+    /// Wakes the driver when something was queued, so it is sent without waiting for a tick.
+    wake: Arc<Notify>,
+    cleanup: Arc<Sender<ConnEvent>>,
+    receipts: ReceiptMap,
     tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
-    send_q: Arc<RwLock<SendQueue>>,
-    recv_q: Arc<Mutex<RecvQueue>>,
-    last_recv: Arc<AtomicU64>,
 }
 
 impl Conn {
     pub async fn new(
         addr: SocketAddr,
-        socket: &Arc<tokio::net::UdpSocket>,
-        cleanup: Arc<Sender<SocketAddr>>,
-        mtu: u16,
+        socket: Arc<dyn DatagramTransport>,
+        cleanup: Arc<Sender<ConnEvent>>,
+        connection: Connection,
     ) -> Self {
-        todo!();
-        // Self {
-        //     addr,
-        //     state: Mutex::new(ConnState::Offline),
-        //     socket: socket.clone(),
-        //     network_recv: Arc::new(Mutex::new(tokio::sync::mpsc::channel(1).1)),
-        // }
-    }
-
-    /// This thread will handle the connection tick.
-    /// The tick is responsible for timing out the connection,
-    /// if packets are not recieved within a certain time frame.
-    pub async fn init_tick(&self, cleanup: Arc<Sender<SocketAddr>>) -> JoinHandle<()> {
-        let notifier = self.disconnect.clone();
+        let (net_tx, net_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(100);
+        let (datagram_tx, datagram_rx) =
+            tokio::sync::mpsc::channel::<(SocketAddr, Vec<u8>)>(DATAGRAM_BUFFER_SIZE);
 
-        tokio::task::spawn(async move {
+        let conn = Self {
+            addr: Arc::new(StdMutex::new(addr)),
+            connection_id: connection.connection_id(),
+            mtu: connection.mtu(),
+            key_share: connection.key_share(),
+            connection: Arc::new(StdMutex::new(connection)),
+            socket,
+            datagram_tx,
+            network_recv: Arc::new(Mutex::new(net_rx)),
+            disconnect: Arc::new(Notify::new()),
+            closed: Arc::new(watch::channel(false).0),
+            wake: Arc::new(Notify::new()),
+            cleanup: cleanup.clone(),
+            receipts: Arc::new(StdMutex::new(HashMap::new())),
+            tasks: Arc::new(Mutex::new(Vec::new())),
+        };
 
-            //
-        })
+        let driver = conn.init_driver(cleanup, datagram_rx, net_tx).await;
+        conn.tasks.lock().await.push(driver);
+
+        conn
+    }
+
+    /// The mtu that was negotiated with this connection during the handshake.
+    pub fn get_mtu(&self) -> u16 {
+        self.mtu
+    }
+
+    /// Our public key from the handshake.
+    pub fn get_key_share(&self) -> KeyShare {
+        self.key_share
+    }
+
+    /// The id the server issued for this connection during the handshake.
+    pub fn get_connection_id(&self) -> u64 {
+        self.connection_id
     }
 
-    /// Handles the incoming messages and processes them, the only messages allowed
-    /// are connection packets.
-    /// Any other packets will be dropped or ignored, this is to prevent any
-    /// malicious packets from being sent to the server.
-    pub async fn init_network(
+    /// Returns the congestion window and round trip time of this connection, for diagnostics.
+    pub async fn stats(&self) -> SendQueueStats {
+        self.connection.lock().unwrap().stats()
+    }
+
+    /// Dispatches a raw datagram recieved by the listener to this connection.
+    /// If the connection can not keep up, the datagram is dropped, as it would be
+    /// by the network.
+    ///
+    /// `from` may be a new address for this connection, the connection only moves to it
+    /// if the datagram is authenticated.
+    pub(crate) fn dispatch(&self, datagram: Vec<u8>, from: SocketAddr) -> bool {
+        self.datagram_tx.try_send((from, datagram)).is_ok()
+    }
+
+    /// This task drives the connection.
+    /// Datagrams from the listener are fed to the connection, and the connection's timers
+    /// are fired when they expire. Everything the connection produces is sent to the peer,
+    /// and payloads are forwarded to `Conn::recv()`.
+    pub async fn init_driver(
         &self,
-        cleanup: Arc<Sender<SocketAddr>>,
+        cleanup: Arc<Sender<ConnEvent>>,
+        mut datagrams: Receiver<(SocketAddr, Vec<u8>)>,
         sender: Sender<Vec<u8>>,
     ) -> JoinHandle<()> {
         let notifier = self.disconnect.clone();
+        let closed = self.closed.clone();
+        let wake = self.wake.clone();
+        let shared_address = self.addr.clone();
+        let mut address = self.get_addr();
+        let connection_id = self.connection_id;
+        let connection = self.connection.clone();
         let socket = self.socket.clone();
-        let address = self.addr.clone();
-        let send_q = self.send_q.clone();
-        let recv_q = self.recv_q.clone();
-        let last_recv = self.last_recv.clone();
+        let receipts = self.receipts.clone();
 
         tokio::task::spawn(async move {
-            let mut buf: [u8; 1024] = [0u8; 1024];
-            'recv: loop {
-                tokio::select! {
-                    _ = notifier.notified() => {
-                        break;
-                    },
-
-                    res = socket.recv_from(&mut buf) => {
-                        // process the packet
-                        // if it's a connection packet, process it.
-                        // if it's not, drop it.
-                        let len: usize;
-                        let addr: SocketAddr;
-                        match res {
-                            Ok((l, o)) => {
-                                len = l;
-                                addr = o;
-                            },
-                            Err(e) => {
-                                continue;
+            'drive: loop {
+                let output = connection
+                    .lock()
+                    .unwrap()
+                    .drive(protocol::util::current_epoch_ms() as u64, None);
+
+                Self::transmit(&socket, address, output.transmits).await;
+
+                for event in output.events {
+                    match event {
+                        Event::Connected => {
+                            log_debug!("[{}] Connection established", address);
+                        }
+                        Event::Payload(payload) => {
+                            if let Err(_) = sender.send(payload).await {
+                                // nothing is listening to this connection anymore.
+                                break 'drive;
                             }
                         }
+                        Event::Receipt(receipt) => {
+                            let (id, delivered) = match receipt {
+                                Receipt::Delivered(id) => (id, true),
+                                Receipt::Failed(id) => (id, false),
+                            };
 
-                        // todo: It might be better to use a mpsc channel
-                        //       instead here...
-                        if addr != address {
-                            continue;
+                            if let Some(tx) = receipts.lock().unwrap().remove(&id) {
+                                // the receipt may have been dropped, or timed out.
+                                let _ = tx.send(delivered);
+                            }
                         }
+                        Event::Disconnected(reason) => {
+                            log_debug!("[{}] Connection closed: {:?}", address, reason);
+                            break 'drive;
+                        }
+                    }
+                }
 
-                        last_recv.store(protocol::util::current_epoch(), std::sync::atomic::Ordering::Release);
-
-                        let mut reader = ByteReader::from(&buf[..len]);
+                let timeout = match output.next_timeout {
+                    Some(timeout) => timeout,
+                    None => break,
+                };
 
-                        if let Ok(packet) = Packets::read(&mut reader) {
-                            let packet = match packet {
-                                Packets::OnlinePacket(v) => v,
-                                _ => {
-                                    continue;
-                                }
-                            };
+                let now = protocol::util::current_epoch_ms() as u64;
+                let sleep = tokio::time::sleep(std::time::Duration::from_millis(
+                    timeout.saturating_sub(now),
+                ));
 
-                            match packet {
-                                OnlinePackets::Datagram(datagram) => {
-                                    let mut recv_queue = recv_q.lock().await;
-
-                                    if let Err(e) = recv_queue.insert(datagram) {
-                                        println!("Failed to insert datagram into recv queue: {:?}", e);
-                                    }
-
-                                    // flush the queue
-                                    let in_sent = recv_queue.flush();
-
-                                    for pk in in_sent.iter() {
-                                        let status = Self::process_packet(&pk, &addr, &sender, &send_q).await;
-                                        if status == ProcessingStatus::Disconnect {
-                                            notifier.notify_waiters();
-                                            break 'recv;
-                                        }
-                                    }
-
-                                    drop(recv_queue);
-                                },
-                                OnlinePackets::Ack(variant) => {
-                                    match variant {
-                                        AckVariant::Ack(ack) => Self::process_ack(ack, &send_q, &recv_q, true).await,
-                                        AckVariant::Nack(nack) => Self::process_ack(nack, &send_q, &recv_q, false).await,
-                                    };
-                                },
-                                _ => {
-                                    // invalid packet
-                                    continue;
-                                }
+                tokio::select! {
+                    _ = notifier.notified() => {
+                        break;
+                    }
+                    _ = wake.notified() => {}
+                    _ = sleep => {}
+                    res = datagrams.recv() => {
+                        let (from, buf) = match res {
+                            Some(datagram) => datagram,
+                            None => {
+                                // the listener has dropped this connection.
+                                break;
                             }
+                        };
+
+                        let authentic = connection
+                            .lock()
+                            .unwrap()
+                            .handle_datagram(&buf, protocol::util::current_epoch_ms() as u64);
+
+                        // only an authenticated, new datagram can move the connection, so the
+                        // session can't be hijacked by spoofing or replaying datagrams.
+                        if from != address && authentic {
+                            log_debug!("[{}] Connection migrated to {}", address, from);
+                            *shared_address.lock().unwrap() = from;
+                            let _ = cleanup.send(ConnEvent::Migrated { connection_id, to: from }).await;
+                            address = from;
                         }
                     }
                 }
             }
+
+            notifier.notify_waiters();
+            closed.send_replace(true);
+
+            // any receipt that is still pending can never be delivered.
+            receipts.lock().unwrap().clear();
+
+            // the connection is no longer processing packets, it should be removed from the listener.
+            let _ = cleanup.send(ConnEvent::Closed(connection_id)).await;
         })
     }
 
-    pub(crate) async fn process_ack(
-        ack: Acknowledgement,
-        send_q: &Arc<RwLock<SendQueue>>,
-        recv_q: &Arc<Mutex<RecvQueue>>,
-        is_ack: bool,
-    ) -> () {
-        let mut send_queue = send_q.write().await;
-        let mut recv_queue = recv_q.lock().await;
-
-        if is_ack {
-            send_queue.ack(ack);
-        } else {
-            let resend = send_queue.nack(ack);
-            for datagram in resend.iter() {
-                // get each set and resend it.
-                let payload = send_queue
-                    .send_raw(datagram.write_to_bytes().unwrap().as_slice())
-                    .await
-                    .unwrap();
+    /// Sends datagrams produced by the connection, errors are ignored as lost
+    /// packets are recovered by the protocol.
+    async fn transmit(
+        socket: &Arc<dyn DatagramTransport>,
+        to: SocketAddr,
+        transmits: Vec<Vec<u8>>,
+    ) {
+        for buf in transmits {
+            if let Err(e) = socket.send_to(&buf, to).await {
+                log_debug!("[{}] Failed to send datagram: {}", to, e);
             }
         }
     }
 
-    pub(crate) async fn process_packet(
-        stream: &[u8],
-        addr: &SocketAddr,
-        conn_sender: &Sender<Vec<u8>>,
-        send_q: &Arc<RwLock<SendQueue>>,
-    ) -> ProcessingStatus {
-        ProcessingStatus::Ok
+    fn write_packet(packet: &SkylinePacket) -> std::io::Result<Vec<u8>> {
+        match packet.write_to_bytes() {
+            Ok(b) => Ok(b.as_slice().to_vec()),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Failed to write skyline packet",
+            )),
+        }
+    }
+
+    fn send_bytes(
+        &self,
+        buf: &[u8],
+        priority: SendPriority,
+        ordering: SendOrdering,
+        channel: Option<u16>,
+    ) -> std::io::Result<()> {
+        let now = protocol::util::current_epoch_ms() as u64;
+
+        if let Err(e) = self
+            .connection
+            .lock()
+            .unwrap()
+            .send_with(buf, priority, ordering, channel, now)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Write Error: {:?}", e),
+            ));
+        }
+
+        self.wake.notify_one();
+        Ok(())
+    }
+}
+
+/// `Low` priority packets are unreliable, a reliable packet is queued with `Medium` priority instead.
+fn reliable_priority(priority: SendPriority) -> SendPriority {
+    match priority {
+        SendPriority::Low => SendPriority::Medium,
+        priority => priority,
+    }
+}
+
+/// The queue priority and ordering for `options`.
+/// Unreliable packets skip the queue, reliable packets are queued with their priority.
+fn send_mode(options: SendOptions) -> (SendPriority, SendOrdering) {
+    match options.reliability {
+        Reliability::Unreliable => (SendPriority::Low, SendOrdering::None),
+        Reliability::UnreliableSequenced => (SendPriority::Low, SendOrdering::Sequenced),
+        Reliability::Reliable => (reliable_priority(options.priority), SendOrdering::None),
+        Reliability::ReliableOrdered => (reliable_priority(options.priority), SendOrdering::Ordered),
+    }
+}
+
+/// The ordering lane of `packet`, by default packets are ordered within their
+/// skyline channel, see `order_channel`.
+fn lane(packet: &SkylinePacket, options: SendOptions) -> u16 {
+    options
+        .ordering_lane
+        .unwrap_or_else(|| order_channel(packet.channel_id()))
+}
+
+#[async_trait]
+impl ConnAdapter for Conn {
+    async fn close(&self, reason: DisconnectReason) -> std::io::Result<()> {
+        let transmits = {
+            let mut connection = self.connection.lock().unwrap();
+            connection.close(reason, protocol::util::current_epoch_ms() as u64);
+
+            let mut transmits = Vec::new();
+            while let Some(buf) = connection.poll_transmit() {
+                transmits.push(buf);
+            }
+            transmits
+        };
+
+        // attempt to notify the peer, we don't care if this fails.
+        Self::transmit(&self.socket, self.get_addr(), transmits).await;
+
+        self.disconnect.notify_waiters();
+        self.closed.send_replace(true);
+
+        let _ = self.cleanup.send(ConnEvent::Closed(self.connection_id)).await;
+
+        let mut tasks = self.tasks.lock().await;
+
+        for task in tasks.drain(..) {
+            task.abort();
+        }
+
+        // the driver is gone, so pending receipts fail now rather than timing out.
+        self.receipts.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    async fn send(&self, packet: &SkylinePacket) -> std::io::Result<()> {
+        self.send_with(packet, SendOptions::default()).await
+    }
+
+    async fn send_with(&self, packet: &SkylinePacket, options: SendOptions) -> std::io::Result<()> {
+        let buf = Self::write_packet(packet)?;
+        let (priority, ordering) = send_mode(options);
+
+        self.send_bytes(buf.as_slice(), priority, ordering, Some(lane(packet, options)))
+    }
+
+    async fn send_confirmed(
+        &self,
+        packet: &SkylinePacket,
+        options: SendOptions,
+        timeout: Duration,
+    ) -> std::io::Result<DeliveryReceipt> {
+        let buf = Self::write_packet(packet)?;
+        let (priority, ordering) = send_mode(options);
+        let (tx, rx) = oneshot::channel::<bool>();
+
+        {
+            let now = protocol::util::current_epoch_ms() as u64;
+            let mut connection = self.connection.lock().unwrap();
+
+            let id = connection
+                .send_tracked(
+                    buf.as_slice(),
+                    priority,
+                    ordering,
+                    Some(lane(packet, options)),
+                    now,
+                )
+                .map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::Other, format!("Write Error: {:?}", e))
+                })?;
+
+            // the connection is still locked, so the driver can't settle the receipt before it's stored.
+            self.receipts.lock().unwrap().insert(id, tx);
+        }
+
+        self.wake.notify_one();
+
+        Ok(DeliveryReceipt::new(async move {
+            match tokio::time::timeout(timeout, rx).await {
+                Ok(Ok(true)) => Ok(()),
+                Ok(Ok(false)) | Ok(Err(_)) => Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "Packet was not delivered",
+                )),
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Packet was not acknowledged in time",
+                )),
+            }
+        }))
+    }
+
+    async fn recv(&self) -> std::io::Result<SkylinePacket> {
+        let mut recv_lock = self.network_recv.lock().await;
+
+        loop {
+            let buf = match recv_lock.recv().await {
+                Some(buf) => buf,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Interrupted,
+                        "Channel closed",
+                    ));
+                }
+            };
+
+            if let Ok(packet) = SkylinePacket::read_from_slice(&buf) {
+                return Ok(packet);
+            } else {
+                log_debug!("[{}] Error: Failed to read skyline packet", self.get_addr());
+            }
+        }
+    }
+
+    async fn send_message(&self, _: protocol::net::tcp::Messages) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "TCP messages can not be sent over a UDP connection",
+        ))
+    }
+
+    async fn send_raw(&self, buf: &[u8]) -> std::io::Result<()> {
+        self.send_bytes(buf, SendPriority::Medium, SendOrdering::None, None)
+    }
+
+    fn get_state(&self) -> ConnState {
+        match self.connection.lock().unwrap().state() {
+            ConnectionState::Connecting => ConnState::Connecting,
+            ConnectionState::Connected => ConnState::Connected,
+            ConnectionState::TimingOut => ConnState::TimingOut,
+            ConnectionState::Disconnected => ConnState::Disconnected,
+        }
+    }
+
+    async fn disconnected(&self) {
+        let mut closed = self.closed.subscribe();
+
+        while !*closed.borrow_and_update() {
+            if closed.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn get_addr(&self) -> SocketAddr {
+        *self.addr.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use binary_util::interfaces::Reader;
+    use protocol::net::udp::{
+        connection::{Connection, ConnectionOptions},
+        proto::{Packets, MAX_MTU_SIZE},
+        transport::DatagramTransport,
+    };
+    use tokio::net::UdpSocket;
+
+    use super::{Conn, ConnEvent};
+    use crate::net::{ConnAdapter, ConnState};
+
+    #[tokio::test]
+    async fn silent_peer_is_disconnected() {
+        let now = protocol::util::current_epoch_ms() as u64;
+        let options = ConnectionOptions {
+            timeout: Duration::from_millis(100),
+            hard_timeout: Duration::from_millis(200),
+            ..ConnectionOptions::default()
+        };
+
+        // the peer connects, then never sends anything again.
+        let mut client = Connection::connect(MAX_MTU_SIZE, ConnectionOptions::default(), now);
+        let request = client.drive(now, None).transmits.remove(0);
+        let connection = match Packets::read_from_slice(&request) {
+            Ok(Packets::ConnectRequest(connect)) => {
+                Connection::accept(&connect, request.len(), options, now).unwrap()
+            }
+            _ => panic!("expected a ConnectRequest"),
+        };
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket: Arc<dyn DatagramTransport> =
+            Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (cleanup, mut events) = tokio::sync::mpsc::channel::<ConnEvent>(8);
+
+        let conn = Conn::new(
+            peer.local_addr().unwrap(),
+            socket,
+            Arc::new(cleanup),
+            connection,
+        )
+        .await;
+
+        tokio::time::timeout(Duration::from_secs(5), conn.disconnected())
+            .await
+            .expect("the connection did not time out");
+        assert_eq!(conn.get_state(), ConnState::Disconnected);
+
+        match events.recv().await {
+            Some(ConnEvent::Closed(id)) => assert_eq!(id, conn.get_connection_id()),
+            _ => panic!("expected the connection to be removed from the listener"),
+        }
+
+        // a connection that is already closed resolves immediately.
+        conn.disconnected().await;
     }
 }
@@ -1,5 +1,6 @@
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
+use async_trait::async_trait;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::Mutex;
@@ -10,14 +11,25 @@ use binary_util::{
     io::ByteReader,
 };
 
-use super::conn::Conn;
-use crate::net::ListenerState;
+use colored::*;
+
+use super::conn::{Conn, ConnEvent};
+use super::UdpOptions;
+use crate::log_debug;
+use crate::net::{ConnAdapter, ListenerState, NetworkInterface};
 use crate::utils::current_epoch;
 use crate::utils::PossiblySocketAddr;
-use protocol::net::udp::proto::offline::{Ping, Pong};
-use protocol::net::udp::proto::{offline::OfflinePackets, Packets};
+use protocol::net::udp::connection::Connection;
+use protocol::net::udp::cookie::CookieJar;
+use protocol::net::udp::proto::offline::{ConnectRetry, Pong, ServerStatus};
+use protocol::net::udp::proto::{
+    offline::OfflinePackets, online::OnlinePackets, Packets, MAX_MTU_SIZE,
+};
 
-pub(crate) type ConnMap = Arc<Mutex<HashMap<SocketAddr, Conn>>>;
+pub(crate) type ConnMap = Arc<Mutex<HashMap<SocketAddr, Arc<Conn>>>>;
+/// The address of every connection by it's connection id, used to find connections
+/// that have moved to a new address.
+pub(crate) type ConnIdMap = Arc<Mutex<HashMap<u64, SocketAddr>>>;
 
 pub struct UdpListener {
     pub addr: SocketAddr,
@@ -26,11 +38,21 @@ pub struct UdpListener {
     close_notifier: Arc<Notify>,
     socket: Arc<tokio::net::UdpSocket>,
     /// A mpsc channel that will send connections to the user and back to the listener.
-    rx_accept_channel: Receiver<Conn>,
-    tx_accept_channel: Sender<Conn>,
+    rx_accept_channel: Mutex<Receiver<Arc<Conn>>>,
+    tx_accept_channel: Sender<Arc<Conn>>,
+    /// Connections report here when they are closed, or their address changes.
+    tx_cleanup: Arc<Sender<ConnEvent>>,
+    rx_cleanup: Option<Receiver<ConnEvent>>,
     /// This is a hash_map of all connections, it contains a buffer channel
     /// that will send data to the connection.
     connections: ConnMap,
+    connection_ids: ConnIdMap,
+    /// Options applied to every accepted connection.
+    options: UdpOptions,
+    /// Issues the cookies clients must echo back before a connection is created.
+    cookies: CookieJar,
+    /// The status sent in offline pongs, `None` if the server is private.
+    status: Option<ServerStatus>,
     state: ListenerState,
 }
 
@@ -41,8 +63,10 @@ impl UdpListener {
         let addr = (address.into() as PossiblySocketAddr).to_socket_addr();
         let close_notifier = Arc::new(Notify::new());
 
-        let (tx_accept_channel, rx_accept_channel) = tokio::sync::mpsc::channel::<Conn>(5);
-        let connections = Arc::new(Mutex::new(HashMap::<SocketAddr, Conn>::new()));
+        let (tx_accept_channel, rx_accept_channel) = tokio::sync::mpsc::channel::<Arc<Conn>>(5);
+        let (tx_cleanup, rx_cleanup) = tokio::sync::mpsc::channel::<ConnEvent>(10);
+        let connections = Arc::new(Mutex::new(HashMap::<SocketAddr, Arc<Conn>>::new()));
+        let connection_ids = Arc::new(Mutex::new(HashMap::<u64, SocketAddr>::new()));
 
         if let None = addr {
             return Err(std::io::Error::new(
@@ -63,14 +87,40 @@ impl UdpListener {
         Ok(Self {
             addr: addr.unwrap(),
             close_notifier,
-            rx_accept_channel,
+            rx_accept_channel: Mutex::new(rx_accept_channel),
             tx_accept_channel,
+            tx_cleanup: Arc::new(tx_cleanup),
+            rx_cleanup: Some(rx_cleanup),
             connections,
+            connection_ids,
             socket,
+            options: UdpOptions::default(),
+            cookies: CookieJar::new(),
+            status: None,
             state: ListenerState::Ready,
         })
     }
 
+    /// Sets the options applied to every connection accepted by this listener.
+    pub fn with_options(mut self, options: UdpOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the secret used to sign handshake cookies.
+    /// Listeners behind the same address should share a secret.
+    pub fn with_cookie_secret(mut self, secret: [u8; 32]) -> Self {
+        self.cookies = CookieJar::with_secret(secret);
+        self
+    }
+
+    /// Publishes the server's status in offline pongs.
+    /// The `peers` field is filled in by the listener when each pong is sent.
+    pub fn with_status(mut self, status: ServerStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
     pub async fn start(&mut self) -> std::io::Result<()> {
         if self.state != ListenerState::Ready {
             return Err(std::io::Error::new(
@@ -81,11 +131,69 @@ impl UdpListener {
 
         self.state = ListenerState::Running;
 
-        let mut buf = [0u8; 1024];
+        let mut buf = vec![0u8; MAX_MTU_SIZE as usize];
         let socket = self.socket.clone();
         let notifier = self.close_notifier.clone();
         let connections = self.connections.clone();
+        let connection_ids = self.connection_ids.clone();
+        let accept = self.tx_accept_channel.clone();
+        let cleanup = self.tx_cleanup.clone();
+        let options = self.options;
+        let cookies = self.cookies.clone();
+        let status = self.status.clone();
 
+        let mut rx_cleanup = self.rx_cleanup.take().unwrap();
+        let cleanup_notifier = self.close_notifier.clone();
+        let cleanup_connections = self.connections.clone();
+        let cleanup_ids = self.connection_ids.clone();
+
+        // removes connections that have been closed from the connection map,
+        // and moves connections that have migrated to their new address.
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cleanup_notifier.notified() => {
+                        break;
+                    }
+                    event = rx_cleanup.recv() => {
+                        let mut sessions = cleanup_connections.lock().await;
+                        let mut ids = cleanup_ids.lock().await;
+
+                        match event {
+                            Some(ConnEvent::Closed(connection_id)) => {
+                                if let Some(addr) = ids.remove(&connection_id) {
+                                    sessions.remove(&addr);
+                                }
+                            }
+                            Some(ConnEvent::Migrated { connection_id, to }) => {
+                                let from = match ids.get(&connection_id) {
+                                    Some(from) => *from,
+                                    None => continue,
+                                };
+
+                                if let Some(conn) = sessions.remove(&from) {
+                                    // a stale connection at the new address is replaced, it is closed in the background
+                                    // as closing reports back to this task.
+                                    if let Some(old) = sessions.insert(to, conn) {
+                                        ids.remove(&old.get_connection_id());
+                                        tokio::task::spawn(async move {
+                                            let _ = old.close(protocol::skyline::connection::DisconnectReason::Closed).await;
+                                        });
+                                    }
+
+                                    ids.insert(connection_id, to);
+                                }
+                            }
+                            None => break,
+                        }
+                    }
+                }
+            }
+        });
+
+        // This is the only task that reads from the socket.
+        // Offline packets are handled here, everything else is dispatched to the
+        // connection the datagram belongs to.
         tokio::task::spawn(async move {
             loop {
                 tokio::select! {
@@ -100,35 +208,124 @@ impl UdpListener {
                                 len = l;
                                 addr = o;
                             },
-                            Err(e) => {
+                            Err(_) => {
                                 continue;
                             }
                         }
 
                         let mut reader = ByteReader::from(&buf[..len]);
 
-                        if let Ok(packet) = OfflinePackets::read(&mut reader) {
+                        let packet = OfflinePackets::read(&mut reader);
+
+                        match packet {
+                            Ok(OfflinePackets::Ping(_)) | Ok(OfflinePackets::ConnectRequest(_)) => {},
+                            _ => {
+                                let sessions = connections.lock().await;
+
+                                // this packet belongs to a connection, if we have one.
+                                let conn = match sessions.get(&addr) {
+                                    Some(conn) => Some(conn),
+                                    // the peer's address may have changed, sealed packets carry the id of their connection.
+                                    // the connection decides whether it moves, once the packet is authenticated.
+                                    None => match Packets::read_from_slice(&buf[..len]) {
+                                        Ok(Packets::OnlinePacket(OnlinePackets::Sealed(sealed))) => {
+                                            match connection_ids.lock().await.get(&sealed.connection_id) {
+                                                Some(old) => sessions.get(old),
+                                                None => None,
+                                            }
+                                        }
+                                        _ => None,
+                                    },
+                                };
+
+                                if let Some(conn) = conn {
+                                    if !conn.dispatch(buf[..len].to_vec(), addr) {
+                                        log_debug!("[{}] Dropping datagram, connection is not keeping up", addr);
+                                    }
+                                }
+                                continue;
+                            }
+                        }
+
+                        if let Ok(packet) = packet {
                             match packet {
                                 OfflinePackets::Ping(ping) => {
-                                    let pong = Pong {
+                                    let status = match status {
+                                        Some(ref status) => {
+                                            let mut status = status.clone();
+                                            status.peers = connections.lock().await.len() as u16;
+                                            Some(status)
+                                        }
+                                        None => None,
+                                    };
+
+                                    let mut pong = Pong {
                                         send: ping.send,
-                                        recv: current_epoch()
+                                        recv: current_epoch(),
+                                        status,
                                     };
 
-                                    send_packet_to(&socket, addr, Packets::Pong(pong)).await.unwrap();
+                                    // the ping wasn't padded to fit the status (and the packet id), reply with the timestamps only.
+                                    if pong.status.is_some() && pong.write_to_bytes().map_or(true, |b| b.as_slice().len() + 1 > len) {
+                                        pong.status = None;
+                                    }
+
+                                    // the ping may be spoofed, never reply with more than we recieved.
+                                    if let Err(_) = send_reply_to(&socket, addr, Packets::Pong(pong), len).await {
+                                        continue;
+                                    }
                                 }
                                 OfflinePackets::ConnectRequest(request) => {
+                                    let verified = match request.cookie {
+                                        Some(ref cookie) => cookies.verify(&addr, cookie),
+                                        None => false,
+                                    };
+
+                                    if !verified {
+                                        // the address hasn't been verified yet, no state is created until
+                                        // the client echoes this cookie back.
+                                        let retry = ConnectRetry {
+                                            cookie: cookies.issue(&addr),
+                                        };
+
+                                        let _ = send_reply_to(&socket, addr, Packets::ConnectRetry(retry), len).await;
+                                        continue;
+                                    }
+
                                     // todo: Check if ip is banned.
-                                    // AFTER THIS PACKET IS SENT, THE CONNECTION
+                                    // AFTER THIS PACKET IS VERIFIED, THE CONNECTION
                                     // IS HANDLED ENTIRELY BY THE CONN STRUCT.
                                     let mut sessions = connections.lock().await;
 
-                                    if !sessions.contains_key(&addr) {
-                                        // let conn = Conn::new(addr, socket.clone(), connections.clone());
-                                        // sessions.insert(addr, conn);
+                                    if let Some(conn) = sessions.get(&addr) {
+                                        // the client didn't recieve our response, the connection will send it again.
+                                        conn.dispatch(buf[..len].to_vec(), addr);
+                                        continue;
                                     }
 
-                                    todo!()
+                                    let now = protocol::util::current_epoch_ms() as u64;
+                                    let connection = match Connection::accept(&request, len, options, now) {
+                                        Ok(connection) => connection,
+                                        Err(e) => {
+                                            log_debug!("[{}] Refusing connection: {:?}", addr, e);
+                                            continue;
+                                        }
+                                    };
+
+                                    // the response is sent by the connection.
+                                    let conn = Arc::new(Conn::new(addr, socket.clone(), cleanup.clone(), connection).await);
+
+                                    connection_ids.lock().await.insert(conn.get_connection_id(), addr);
+                                    sessions.insert(addr, conn.clone());
+
+                                    // the accept queue and the cleanup channel are drained by tasks that
+                                    // need the connection map, so it is released before waiting on them.
+                                    drop(sessions);
+
+                                    if let Err(_) = accept.send(conn.clone()).await {
+                                        // the listener is no longer accepting connections, closing removes it from the map.
+                                        let _ = conn.close(protocol::skyline::connection::DisconnectReason::NotAccepting).await;
+                                    }
                                 },
                                 _ => {}
                             };
@@ -138,7 +335,7 @@ impl UdpListener {
             }
         });
 
-        todo!()
+        Ok(())
     }
 
     pub async fn close(&self) {
@@ -146,12 +343,63 @@ impl UdpListener {
     }
 }
 
-async fn send_packet_to(
+#[async_trait]
+impl NetworkInterface for UdpListener {
+    async fn new(addr: &str) -> std::io::Result<Self> {
+        Self::bind(addr).await
+    }
+
+    async fn bind(&mut self) -> std::io::Result<()> {
+        self.start().await
+    }
+
+    async fn accept(&self) -> std::io::Result<Arc<dyn ConnAdapter>> {
+        let mut rx = self.rx_accept_channel.lock().await;
+
+        match rx.recv().await {
+            Some(conn) => Ok(conn),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "Listener is closed.",
+            )),
+        }
+    }
+
+    async fn close(&mut self) -> std::io::Result<()> {
+        self.close_notifier.notify_waiters();
+        self.state = ListenerState::Closed;
+
+        let mut sessions = self.connections.lock().await;
+
+        for (_, conn) in sessions.drain() {
+            let _ = conn
+                .close(protocol::skyline::connection::DisconnectReason::Closed)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    fn get_name(&self) -> &str {
+        "udp"
+    }
+}
+
+/// Sends a reply to an unverified peer.
+/// The reply is dropped if it is larger than the request, so the listener
+/// can't be used to amplify traffic towards a spoofed address.
+async fn send_reply_to(
     socket: &Arc<UdpSocket>,
     to: SocketAddr,
     packet: Packets,
+    request_len: usize,
 ) -> std::io::Result<()> {
     if let Ok(b) = packet.write_to_bytes() {
+        if b.as_slice().len() > request_len {
+            log_debug!("[{}] Dropping offline reply, the request was not padded", to);
+            return Ok(());
+        }
+
         socket.send_to(&b.as_slice(), to).await?;
     }
 
@@ -1,2 +1,5 @@
 pub mod conn;
 pub mod listener;
+
+/// Options that are applied to every connection accepted by the `UdpListener`.
+pub type UdpOptions = protocol::net::udp::connection::ConnectionOptions;